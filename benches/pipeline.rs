@@ -0,0 +1,98 @@
+//! Measures the cost of round trips against an in-process server, comparing sequential requests
+//! with pipelined batches for several values of `Client::set_flush_every_n`. Writes are also
+//! compared with setting every value in a single commit using `set_tree`.
+//!
+//! Run using `cargo bench --no-default-features --features client,testutil`. The server answers
+//! every request immediately, so the results show protocol overhead rather than server work.
+//! Larger batches stall once the socket buffers fill, especially for writes since each one
+//! carries an `Info`, so compare the thresholds before changing the default of 64.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use irmin::client::testutil::MockServer;
+use irmin::prelude::*;
+
+/// Number of requests sent by each iteration
+const REQUESTS: usize = 1_000;
+
+/// Flush thresholds compared for pipelined requests, 0 writes the whole batch before flushing
+const FLUSH_EVERY_N: [usize; 6] = [1, 16, 64, 256, 1024, 0];
+
+fn pipeline(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    // Server that responds to `store.find` with an empty value and accepts every write
+    let server = rt
+        .block_on(MockServer::new(|command, _| match command {
+            "store.find" => MockServer::ok(Some(Bytes::empty())),
+            _ => MockServer::ok(()),
+        }))
+        .unwrap();
+    let client = rt.block_on(server.client::<Bytes, Blake2b>()).unwrap();
+    let store = client.store();
+    let keys: Vec<Key> = (0..REQUESTS)
+        .map(|i| Key::new([i.to_string().as_str()]))
+        .collect();
+    let items: Vec<(Key, Bytes)> = keys
+        .iter()
+        .map(|key| (key.clone(), Bytes::empty()))
+        .collect();
+
+    let mut group = c.benchmark_group("read");
+    group.throughput(Throughput::Elements(REQUESTS as u64));
+    group.bench_function("find", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                for key in &keys {
+                    store.find::<Bytes>(key).await.unwrap();
+                }
+            })
+        })
+    });
+    for n in FLUSH_EVERY_N {
+        group.bench_with_input(BenchmarkId::new("find_many", n), &n, |b, &n| {
+            client.set_flush_every_n(n);
+            b.iter(|| rt.block_on(store.find_many::<Bytes>(&keys)).unwrap())
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("write");
+    group.throughput(Throughput::Elements(REQUESTS as u64));
+    client.set_flush_every_n(64);
+    group.bench_function("set", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                for key in &keys {
+                    store.set(key, Bytes::empty(), Info::new()).await.unwrap();
+                }
+            })
+        })
+    });
+    group.bench_function("set_tree", |b| {
+        b.iter(|| {
+            let mut builder = TreeBuilder::new();
+            for key in &keys {
+                builder = builder.add(key.clone(), Bytes::empty());
+            }
+            let tree = builder.build().unwrap();
+            rt.block_on(store.set_tree(&Key::empty(), &tree, Info::new()))
+                .unwrap()
+        })
+    });
+    for n in FLUSH_EVERY_N {
+        group.bench_with_input(BenchmarkId::new("set_many", n), &n, |b, &n| {
+            client.set_flush_every_n(n);
+            b.iter(|| rt.block_on(store.set_many(&items, Info::new())).unwrap())
+        });
+    }
+    group.finish();
+
+    rt.block_on(client.close()).unwrap();
+}
+
+criterion_group!(benches, pipeline);
+criterion_main!(benches);
@@ -43,6 +43,6 @@ fn link() {
 }
 
 fn main() {
-    #[cfg(feature = "bindings")]
+    #[cfg(feature = "ocaml")]
     link()
 }
@@ -10,9 +10,10 @@ ocaml! {
 
 impl Config {
     pub fn new(ctx: &mut Context, root: impl AsRef<str>) -> Config {
-        let cr = &mut ctx.rt;
-        let root = root.as_ref().to_ocaml(cr).root();
-        let x: BoxRoot<Config> = config(cr, &root);
-        x.to_rust(cr)
+        ctx.with(|cr| {
+            let root = root.as_ref().to_ocaml(cr).root();
+            let x: BoxRoot<Config> = config(cr, &root);
+            x.to_rust(cr)
+        })
     }
 }
@@ -26,6 +26,14 @@ impl Context {
         gen.build_with_context(&mut ctx);
         ctx
     }
+
+    /// Run `f` with access to the OCaml runtime, every call into OCaml goes through this. It
+    /// takes `&mut self` so two calls can never be active at the same time, a wrapper that
+    /// re-enters the runtime from inside `f` is rejected by the borrow checker instead of
+    /// failing at runtime.
+    pub(crate) fn with<R>(&mut self, f: impl FnOnce(&mut OCamlRuntime) -> R) -> R {
+        f(&mut self.rt)
+    }
 }
 
 /// A `Builder` is used to create a `Context`, currently only one `Builder` per process is
@@ -63,11 +71,12 @@ impl Builder {
     }
 
     fn build_with_context(self, ctx: &mut Context) {
-        let cr = &mut ctx.rt;
-        let store = self.store.to_ocaml(cr).root();
-        let hash = self.hash.to_ocaml(cr).root();
-        let contents = self.contents.to_ocaml(cr).root();
-        store_gen(cr, &store, &hash, &contents);
+        ctx.with(|cr| {
+            let store = self.store.to_ocaml(cr).root();
+            let hash = self.hash.to_ocaml(cr).root();
+            let contents = self.contents.to_ocaml(cr).root();
+            store_gen(cr, &store, &hash, &contents);
+        })
     }
 
     /// Build a new `Context` using the specified store type
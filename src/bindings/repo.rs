@@ -10,9 +10,10 @@ ocaml! {
 
 impl Repo {
     pub fn new(ctx: &mut Context, cfg: &Config) -> Repo {
-        let cr = &mut ctx.rt;
-        let cfg = cfg.to_ocaml(cr).root();
-        let x: BoxRoot<Repo> = repo(cr, &cfg);
-        x.to_rust(cr)
+        ctx.with(|cr| {
+            let cfg = cfg.to_ocaml(cr).root();
+            let x: BoxRoot<Repo> = repo(cr, &cfg);
+            x.to_rust(cr)
+        })
     }
 }
@@ -14,42 +14,47 @@ ocaml! {
 
 impl Store {
     pub fn master(ctx: &mut Context, repo: &Repo) -> Store {
-        let cr = &mut ctx.rt;
-        let repo = repo.to_ocaml(cr).root();
-        let x: BoxRoot<Store> = store_master(cr, &repo);
-        x.to_rust(cr)
+        ctx.with(|cr| {
+            let repo = repo.to_ocaml(cr).root();
+            let x: BoxRoot<Store> = store_master(cr, &repo);
+            x.to_rust(cr)
+        })
     }
 
     pub fn mem(&self, ctx: &mut Context, key: &Key) -> bool {
-        let cr = &mut ctx.rt;
-        let store = self.to_ocaml(cr).root();
-        let key = key.to_ocaml(cr).root();
-        let x: BoxRoot<bool> = store_mem(cr, &store, &key);
-        x.to_rust(cr)
+        ctx.with(|cr| {
+            let store = self.to_ocaml(cr).root();
+            let key = key.to_ocaml(cr).root();
+            let x: BoxRoot<bool> = store_mem(cr, &store, &key);
+            x.to_rust(cr)
+        })
     }
 
     pub fn find(&self, ctx: &mut Context, key: &Key) -> Option<String> {
-        let cr = &mut ctx.rt;
-        let store = self.to_ocaml(cr).root();
-        let key = key.to_ocaml(cr).root();
-        let x = store_find(cr, &store, &key);
-        x.to_rust(cr)
+        ctx.with(|cr| {
+            let store = self.to_ocaml(cr).root();
+            let key = key.to_ocaml(cr).root();
+            let x = store_find(cr, &store, &key);
+            x.to_rust(cr)
+        })
     }
 
     pub fn remove(&self, ctx: &mut Context, key: &Key, msg: impl AsRef<str>) {
-        let cr = &mut ctx.rt;
-        let store = self.to_ocaml(cr).root();
-        let key = key.to_ocaml(cr).root();
-        let info = msg.as_ref().to_ocaml(cr).root();
-        let _: BoxRoot<()> = store_remove(cr, &store, &key, &info);
+        ctx.with(|cr| {
+            let store = self.to_ocaml(cr).root();
+            let key = key.to_ocaml(cr).root();
+            let info = msg.as_ref().to_ocaml(cr).root();
+            let _: BoxRoot<()> = store_remove(cr, &store, &key, &info);
+        })
     }
 
     pub fn set(&self, ctx: &mut Context, key: &Key, value: impl AsRef<[u8]>, msg: impl AsRef<str>) {
-        let cr = &mut ctx.rt;
-        let store = self.to_ocaml(cr).root();
-        let key = key.to_ocaml(cr).root();
-        let info = msg.as_ref().to_ocaml(cr).root();
-        let value = value.as_ref().to_ocaml(cr).root();
-        let _: BoxRoot<()> = store_set(cr, &store, &key, &value, &info);
+        ctx.with(|cr| {
+            let store = self.to_ocaml(cr).root();
+            let key = key.to_ocaml(cr).root();
+            let info = msg.as_ref().to_ocaml(cr).root();
+            let value = value.as_ref().to_ocaml(cr).root();
+            let _: BoxRoot<()> = store_set(cr, &store, &key, &value, &info);
+        })
     }
 }
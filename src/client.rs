@@ -1,327 +1,3306 @@
-use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::io::*;
 use tokio::net::{TcpStream, ToSocketAddrs, UnixStream};
+use tokio::sync::Mutex;
 
-use crate::{Commit, Hash, Info, Key, Tree, Type};
+use crate::{irmin, Commit, Concrete, Diff, Hash, Info, Key, KeyLimits, Kind, Tree, Type};
 
 use blake2::Digest;
 
+mod builder;
+mod cache;
+mod database;
+mod list_stream;
+mod pool;
+#[cfg(feature = "replay")]
+mod replay;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
+mod timeout;
+mod wire_log;
+pub use builder::ClientBuilder;
+pub use cache::{CachingStore, CachingTree};
+pub use database::Database;
+pub use list_stream::ListStream;
+pub use pool::{Pool, Pooled};
+#[cfg(feature = "replay")]
+use replay::Recorder;
+#[cfg(feature = "replay")]
+pub use replay::{Record, Replay, ReplayClient};
+use timeout::{Deadline, Timeouts};
+use wire_log::WireLog;
+
 pub type Tcp = TcpStream;
 pub type Unix = UnixStream;
 
 /// irmin-server client implementation
 pub struct Client<Socket, Contents: Type, H: Hash> {
-    conn: RefCell<BufStream<Socket>>,
+    conn: Arc<Connection<Socket>>,
+    keepalive: std::sync::Mutex<Option<tokio::task::JoinHandle<()>>>,
+    shutdown: fn(&Connection<Socket>),
+    endpoint: Option<SocketAddr>,
     _t: std::marker::PhantomData<(Contents, H)>,
 }
 
+/// Connection state shared between a `Client` and its background tasks
+struct Connection<Socket> {
+    stream: Mutex<BufStream<Deadline<Socket>>>,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    dead: AtomicBool,
+    poisoned: Arc<AtomicBool>,
+    timeouts: Arc<Timeouts>,
+    wire_log: WireLog,
+    flush_every_n: AtomicUsize,
+    generation: AtomicU64,
+    key_limits: std::sync::Mutex<KeyLimits>,
+    #[cfg(feature = "replay")]
+    recorder: Arc<Recorder>,
+}
+
+/// Number of bytes transferred by a `Client`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// The kind and hash of the node found at a key
+#[derive(Debug, Clone, PartialEq, Type)]
+pub struct NodeStat<H: Hash> {
+    pub kind: Kind,
+    pub hash: H,
+}
+
+/// Information about the server a `Client` is connected to
+#[derive(Debug, Clone, PartialEq, Eq, Type)]
+pub struct ServerInfo {
+    pub version: String,
+    pub hash: String,
+    pub contents: String,
+}
+
 /// Wrapper around `Client` to provide access to methods defined for stores
 pub struct Store<'a, Socket, Contents: Type, H: Hash> {
     client: &'a Client<Socket, Contents, H>,
+    info: Option<Info>,
 }
 
 const V1: &str = "V1";
 
-impl<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type, H: Hash> Client<Socket, Contents, H> {
-    async fn write_handshake(&self) -> std::io::Result<()> {
-        let mut conn = self.conn.borrow_mut();
-        let hash = format!("{:x}\n", blake2::Blake2b::digest(V1.as_bytes()));
-        conn.write_all(hash.as_bytes()).await?;
-        conn.flush().await?;
+/// Number of times `Store::update_with` retries after a conflict
+const UPDATE_RETRIES: usize = 8;
+
+/// Size of the chunks used when writing large messages incrementally
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// `std::io::Write` implementation that only counts the bytes written to it
+struct Counter(usize);
+
+impl std::io::Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
         Ok(())
     }
+}
 
-    async fn read_handshake(&self) -> std::io::Result<bool> {
-        let mut conn = self.conn.borrow_mut();
-        let mut line = String::new();
-        conn.read_line(&mut line).await?;
-        let hash = format!("{:x}\n", blake2::Blake2b::digest(V1.as_bytes()));
-        Ok(line == hash)
+/// Message that is already encoded, it is written as-is
+struct Raw<'a>(&'a [u8]);
+
+impl<'a> Type for Raw<'a> {
+    fn encode_bin<W: std::io::Write>(&self, dest: &mut W) -> std::io::Result<usize> {
+        dest.write_all(self.0)?;
+        Ok(self.0.len())
     }
 
-    async fn do_handshake(&self) -> std::io::Result<()> {
-        self.write_handshake().await?;
-        let ok = self.read_handshake().await?;
-        if !ok {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::ConnectionRefused,
-                "Invalid handshake",
-            ));
+    fn decode_bin<R: std::io::Read>(_src: &mut R) -> std::io::Result<Self> {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "Cannot decode into a reference",
+        ))
+    }
+}
+
+/// Encoded size above which `Store::set_tree` builds a concrete tree on the server in parts
+const LARGE_TREE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Part of a concrete tree, encoded the same as `Tree::Concrete` without taking ownership
+struct Subtree<'a, T>(&'a Concrete<T>);
+
+impl<'a, T: Type> Type for Subtree<'a, T> {
+    fn encode_bin<W: std::io::Write>(&self, dest: &mut W) -> std::io::Result<usize> {
+        // `Concrete` is the third variant of `Tree`
+        let n = 2isize.encode_bin(dest)?;
+        Ok(n + self.0.encode_bin(dest)?)
+    }
+
+    fn decode_bin<R: std::io::Read>(_src: &mut R) -> std::io::Result<Self> {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "Cannot decode into a reference",
+        ))
+    }
+}
+
+/// Default number of pipelined requests written before flushing, see
+/// `Client::set_flush_every_n`
+const FLUSH_EVERY_N: usize = 64;
+
+/// Encoded hash, used to keep track of visited commits since `Hash` doesn't require
+/// `std::hash::Hash`
+fn hash_id<H: Hash>(hash: &H) -> std::io::Result<Vec<u8>> {
+    let mut id = Vec::new();
+    hash.encode_bin(&mut id)?;
+    Ok(id)
+}
+
+/// Waker used to poll the socket once when there's no task to wake
+struct NoopWaker;
+
+impl std::task::Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+/// Reading from the socket only hits EOF when the server hangs up
+fn closed(e: std::io::Error) -> std::io::Error {
+    if e.kind() == ErrorKind::UnexpectedEof {
+        crate::Error::ConnectionClosed.into()
+    } else {
+        e
+    }
+}
+
+/// Prefix of the error message the server responds with when it doesn't support a command
+const UNKNOWN_COMMAND: &str = "unknown command";
+
+/// Errors sent by the server are returned with `ErrorKind::Other`, without an `Error` attached
+fn is_server_error(e: &std::io::Error) -> bool {
+    e.kind() == ErrorKind::Other && crate::Error::of_io(e).is_none()
+}
+
+/// Check if `e` was sent by a server that doesn't support the command, used to fall back to
+/// older commands
+fn is_unknown_command(e: &std::io::Error) -> bool {
+    is_server_error(e)
+        && e.to_string()
+            .to_ascii_lowercase()
+            .starts_with(UNKNOWN_COMMAND)
+}
+
+/// Report that the server doesn't support `command` using `ErrorKind::Unsupported`, used by
+/// methods that can't fall back to older commands. Other errors are returned unchanged.
+fn unsupported(command: &str, e: std::io::Error) -> std::io::Error {
+    if is_unknown_command(&e) {
+        Error::new(
+            ErrorKind::Unsupported,
+            format!("Server does not support {}: {}", command, e),
+        )
+    } else {
+        e
+    }
+}
+
+impl<Socket: Unpin + AsyncRead + AsyncWrite> Connection<Socket> {
+    /// Flush and shut down the socket without waiting, used when a client is dropped without
+    /// being closed. Nothing is done if the connection is in use, poisoned or there's no runtime
+    /// to drive the socket, anything that can't be written immediately is lost.
+    fn shutdown_now(&self) {
+        if self.poisoned.load(Ordering::Relaxed) || tokio::runtime::Handle::try_current().is_err() {
+            return;
+        }
+        let mut conn = match self.stream.try_lock() {
+            Ok(conn) => conn,
+            Err(_) => return,
+        };
+        let waker = std::task::Waker::from(Arc::new(NoopWaker));
+        let mut cx = std::task::Context::from_waker(&waker);
+        let mut conn = std::pin::Pin::new(&mut *conn);
+        if let std::task::Poll::Ready(Ok(())) = conn.as_mut().poll_flush(&mut cx) {
+            let _ = conn.poll_shutdown(&mut cx);
         }
-        Ok(())
     }
 
     async fn write_message(
         &self,
-        conn: &mut BufStream<Socket>,
+        conn: &mut BufStream<Deadline<Socket>>,
         msg: impl Type,
     ) -> std::io::Result<()> {
         let mut data = Vec::new();
         msg.encode_bin(&mut data)?;
+        self.wire_log.frame('>', &data);
         let len = data.len() as i64;
         conn.write_all(&len.to_be_bytes()).await?;
         conn.write_all(data.as_slice()).await?;
-        conn.flush().await?;
+        self.bytes_sent
+            .fetch_add(8 + data.len() as u64, Ordering::Relaxed);
 
         Ok(())
     }
 
-    async fn read_message<T: Type>(&self, conn: &mut BufStream<Socket>) -> std::io::Result<T> {
+    async fn read_len(&self, conn: &mut BufStream<Deadline<Socket>>) -> std::io::Result<usize> {
         let mut len_buf = [0u8; 8];
-        conn.read_exact(&mut len_buf).await?;
+        conn.read_exact(&mut len_buf).await.map_err(closed)?;
         let len = i64::from_be_bytes(len_buf);
-        let mut data = vec![0u8; len as usize];
-        conn.read_exact(data.as_mut_slice()).await?;
-        T::decode_bin(&mut data.as_slice())
+        self.bytes_received.fetch_add(8, Ordering::Relaxed);
+
+        // Negative lengths, or lengths that don't fit in a `usize` on 32-bit targets, mean the
+        // rest of the message can't be skipped
+        usize::try_from(len).map_err(|_| {
+            self.poisoned.store(true, Ordering::Relaxed);
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid message length: {}", len),
+            )
+        })
+    }
+
+    /// Read a length-prefixed message without decoding it
+    async fn read_frame(&self, conn: &mut BufStream<Deadline<Socket>>) -> std::io::Result<Vec<u8>> {
+        let len = self.read_len(conn).await?;
+
+        // Fail instead of aborting when the length is more than can be allocated
+        let mut data = Vec::new();
+        if data.try_reserve_exact(len).is_err() {
+            self.poisoned.store(true, Ordering::Relaxed);
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Message is too large to allocate: {} bytes", len),
+            ));
+        }
+        data.resize(len, 0);
+        conn.read_exact(data.as_mut_slice()).await.map_err(closed)?;
+        self.bytes_received
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.wire_log.frame('<', &data);
+        Ok(data)
+    }
+
+    async fn read_message<T: Type>(
+        &self,
+        conn: &mut BufStream<Deadline<Socket>>,
+    ) -> std::io::Result<T> {
+        let data = self.read_frame(conn).await?;
+        Key::with_decode_limits(self.key_limits(), || crate::r#type::decode_vec(data))
+    }
+
+    fn key_limits(&self) -> KeyLimits {
+        *self.key_limits.lock().unwrap()
     }
 
-    async fn request(&self, command: impl AsRef<str>, msg: impl Type) -> std::io::Result<()> {
-        let mut conn = self.conn.borrow_mut();
+    async fn write_request(
+        &self,
+        conn: &mut BufStream<Deadline<Socket>>,
+        command: impl AsRef<str>,
+        msg: impl Type,
+    ) -> std::io::Result<()> {
+        self.wire_log.line('>', || command.as_ref().to_string());
         conn.write_all(command.as_ref().as_bytes()).await?;
         conn.write_u8(b'\n').await?;
-        self.write_message(&mut *conn, msg).await?;
+        self.write_message(conn, msg).await
+    }
+
+    async fn request(
+        &self,
+        conn: &mut BufStream<Deadline<Socket>>,
+        command: impl AsRef<str>,
+        msg: impl Type,
+    ) -> std::io::Result<()> {
+        self.write_request(conn, command, msg).await?;
+        conn.flush().await?;
 
         Ok(())
     }
 
-    async fn response<T: Type>(&self) -> std::io::Result<T> {
-        let mut conn = self.conn.borrow_mut();
+    /// Like `request`, for messages that end with a tree. The message is measured first, then
+    /// written in chunks so the full encoding of the tree is never held in memory
+    async fn request_tree<T: Type, H: Hash>(
+        &self,
+        conn: &mut BufStream<Deadline<Socket>>,
+        command: impl AsRef<str>,
+        prefix: impl Type,
+        tree: &Tree<T, H>,
+    ) -> std::io::Result<()> {
+        let mut counter = Counter(0);
+        prefix.encode_bin(&mut counter)?;
+        tree.encode_bin(&mut counter)?;
+        let len = counter.0;
+
+        self.wire_log.line('>', || command.as_ref().to_string());
+        conn.write_all(command.as_ref().as_bytes()).await?;
+        conn.write_u8(b'\n').await?;
+        conn.write_all(&(len as i64).to_be_bytes()).await?;
 
-        let mut status_buf = [0];
-        conn.read_exact(&mut status_buf).await?;
-        if status_buf[0] > 0 {
-            let s = self.read_message::<String>(&mut conn).await?;
-            return Err(Error::new(ErrorKind::Other, s));
-        } else {
-            self.read_message::<T>(&mut *conn).await
+        let mut buf = Vec::with_capacity(CHUNK_SIZE);
+        prefix.encode_bin(&mut buf)?;
+        let mut encoder = tree.encoder();
+        let mut written = 0;
+        loop {
+            let more = encoder.fill(&mut buf, CHUNK_SIZE)?;
+            self.wire_log.frame('>', &buf);
+            conn.write_all(buf.as_slice()).await?;
+            written += buf.len();
+            buf.clear();
+            if !more {
+                break;
+            }
         }
-    }
+        conn.flush().await?;
+        self.bytes_sent
+            .fetch_add(8 + written as u64, Ordering::Relaxed);
+        debug_assert_eq!(written, len);
 
-    /// Close the client
-    pub async fn close(self) -> std::io::Result<()> {
-        self.conn.into_inner().shutdown().await?;
         Ok(())
     }
 
-    /// Ping the server, used to check to ensure the client is connected
-    pub async fn ping(&self) -> std::io::Result<()> {
-        self.request("ping", ()).await?;
-        self.response::<()>().await?;
+    async fn read_status(&self, conn: &mut BufStream<Deadline<Socket>>) -> std::io::Result<()> {
+        let mut status_buf = [0];
+        conn.read_exact(&mut status_buf).await.map_err(closed)?;
+        self.wire_log
+            .line('<', || format!("status {}", status_buf[0]));
+        if status_buf[0] > 0 {
+            let s = self.read_message::<String>(conn).await?;
+            return Err(Error::new(ErrorKind::Other, s));
+        }
         Ok(())
     }
 
-    /// Access store methods
-    pub fn store<'a>(&'a self) -> Store<'a, Socket, Contents, H> {
-        Store { client: self }
+    async fn response<T: Type>(
+        &self,
+        conn: &mut BufStream<Deadline<Socket>>,
+    ) -> std::io::Result<T> {
+        self.read_status(conn).await?;
+        self.read_message::<T>(conn).await
     }
 
-    /// Set the client's branch
-    pub async fn set_current_branch(&self, branch: impl AsRef<str>) -> std::io::Result<()> {
-        self.request("set_current_branch", branch.as_ref()).await?;
-        self.response::<()>().await?;
+    fn check_alive(&self) -> std::io::Result<()> {
+        if self.poisoned.load(Ordering::Relaxed) {
+            return Err(Error::new(
+                ErrorKind::NotConnected,
+                "Connection is in an unknown state after a timeout or cancelled request",
+            ));
+        }
+        if self.dead.load(Ordering::Relaxed) {
+            return Err(Error::new(ErrorKind::NotConnected, "Connection is dead"));
+        }
         Ok(())
     }
 
-    /// Get the client's branch
-    pub async fn get_current_branch(&self) -> std::io::Result<String> {
-        self.request("get_current_branch", ()).await?;
-        self.response::<String>().await
-    }
-}
+    /// Run a request/response cycle. If `cycle` is dropped before it completes, because the
+    /// caller's future was cancelled or it panicked, part of a request or response may be left
+    /// on the stream, so the connection is poisoned.
+    async fn cycle<R>(
+        &self,
+        cycle: impl std::future::Future<Output = std::io::Result<R>>,
+    ) -> std::io::Result<R> {
+        struct Guard<'a>(Option<&'a AtomicBool>);
 
-impl<C: Type, H: Hash> Client<TcpStream, C, H> {
-    /// Create a new client connected to a TCP server
-    pub async fn new(s: impl ToSocketAddrs) -> std::io::Result<Client<TcpStream, C, H>> {
-        let conn = TcpStream::connect(s).await?;
-        let conn = RefCell::new(BufStream::new(conn));
-        let client = Client {
-            conn,
-            _t: std::marker::PhantomData,
-        };
-        client.do_handshake().await?;
-        Ok(client)
+        impl Drop for Guard<'_> {
+            fn drop(&mut self) {
+                if let Some(poisoned) = self.0 {
+                    poisoned.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+
+        let mut guard = Guard(Some(&self.poisoned));
+        let res = cycle.await;
+        guard.0 = None;
+        res
     }
-}
 
-impl<C: Type, H: Hash> Client<UnixStream, C, H> {
-    /// Create a new client connected to a Unix socket
-    pub async fn new(s: impl AsRef<std::path::Path>) -> std::io::Result<Client<UnixStream, C, H>> {
-        let conn = UnixStream::connect(s).await?;
-        let conn = RefCell::new(BufStream::new(conn));
-        let client = Client {
-            conn,
-            _t: std::marker::PhantomData,
-        };
-        client.do_handshake().await?;
-        Ok(client)
+    /// Send a request and read its response, holding the connection lock for the whole cycle
+    /// so requests issued concurrently (including keepalive pings) never interleave
+    async fn call<T: Type>(&self, command: impl AsRef<str>, msg: impl Type) -> std::io::Result<T> {
+        self.check_alive()?;
+        let mut conn = self.stream.lock().await;
+        self.cycle(async {
+            self.request(&mut conn, command, msg).await?;
+            self.response(&mut conn).await
+        })
+        .await
     }
-}
 
-impl<'a, Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type, H: Hash>
-    Store<'a, Socket, Contents, H>
-{
-    /// Set the value associated with a key
-    pub async fn set<T: Type>(&self, key: &Key, value: T, info: Info) -> std::io::Result<()> {
-        self.client.request("store.set", (key, info, value)).await?;
-        self.client.response().await
+    /// Send the same command once for each message, writing requests before reading any of
+    /// their responses so a batch of up to `flush_every_n` requests only costs a single round
+    /// trip. Every response is read even if some of them are errors, the first error is
+    /// returned.
+    async fn call_many<T: Type, M: Type>(
+        &self,
+        command: impl AsRef<str>,
+        msgs: impl IntoIterator<Item = M>,
+    ) -> std::io::Result<Vec<T>> {
+        self.check_alive()?;
+        let flush_every_n = self.flush_every_n.load(Ordering::Relaxed);
+        let mut conn = self.stream.lock().await;
+        self.cycle(async {
+            let mut results = Vec::new();
+            let mut pending = 0;
+            for msg in msgs {
+                self.write_request(&mut conn, command.as_ref(), msg).await?;
+                pending += 1;
+                if pending == flush_every_n {
+                    self.read_responses(&mut conn, pending, &mut results)
+                        .await?;
+                    pending = 0;
+                }
+            }
+            self.read_responses(&mut conn, pending, &mut results)
+                .await?;
+            results.into_iter().collect()
+        })
+        .await
     }
 
-    /// Set the tree associated with a key
-    pub async fn set_tree<T: Type>(
+    /// Flush the requests written by `call_many` and read `count` responses
+    async fn read_responses<T: Type>(
         &self,
-        key: &Key,
-        tree: &Tree<T, H>,
-        info: Info,
+        conn: &mut BufStream<Deadline<Socket>>,
+        count: usize,
+        results: &mut Vec<std::io::Result<T>>,
     ) -> std::io::Result<()> {
-        self.client
-            .request("store.set_tree", (key, info, tree))
-            .await?;
-        self.client.response().await
+        conn.flush().await?;
+        for _ in 0..count {
+            results.push(self.response(conn).await);
+        }
+        Ok(())
     }
 
-    /// Find a value in the store
-    pub async fn find<T: Type>(&self, key: &Key) -> std::io::Result<Option<T>> {
-        self.client.request("store.find", key).await?;
-        self.client.response().await
+    /// Like `call`, returning the encoded response without decoding it
+    async fn call_raw(&self, command: impl AsRef<str>, msg: impl Type) -> std::io::Result<Vec<u8>> {
+        self.check_alive()?;
+        let mut conn = self.stream.lock().await;
+        self.cycle(async {
+            self.request(&mut conn, command, msg).await?;
+            self.read_status(&mut conn).await?;
+            self.read_frame(&mut conn).await
+        })
+        .await
     }
 
-    /// Find a tree in the store
-    pub async fn find_tree<T: Type>(&self, key: &Key) -> std::io::Result<Option<Tree<T, H>>> {
-        self.client.request("store.find_tree", key).await?;
-        self.client.response().await
+    /// Like `call`, using `request_tree` to send the request
+    async fn call_tree<T: Type, H: Hash, R: Type>(
+        &self,
+        command: impl AsRef<str>,
+        prefix: impl Type,
+        tree: &Tree<T, H>,
+    ) -> std::io::Result<R> {
+        self.check_alive()?;
+        let mut conn = self.stream.lock().await;
+        self.cycle(async {
+            self.request_tree(&mut conn, command, prefix, tree).await?;
+            self.response(&mut conn).await
+        })
+        .await
     }
 
-    /// Check if a key is set to a value
-    pub async fn mem<T: Type>(&self, key: &Key) -> std::io::Result<bool> {
-        self.client.request("store.mem", key).await?;
-        self.client.response().await
-    }
+    /// Read the response to a write, returning the commit it created. Servers that don't
+    /// acknowledge writes with the new commit respond with an empty message, in that case the
+    /// head is requested while the connection is still locked so no other request made using
+    /// this client can move it first.
+    async fn read_commit<H: Hash>(
+        &self,
+        conn: &mut BufStream<Deadline<Socket>>,
+    ) -> std::io::Result<Commit<H>> {
+        self.read_status(conn).await?;
+        let ack = self.read_frame(conn).await?;
+        if !ack.is_empty() {
+            return Key::with_decode_limits(self.key_limits(), || {
+                crate::r#type::decode_slice(&ack)
+            });
+        }
 
-    /// Check if a key is set to a tree
-    pub async fn mem_tree<T: Type>(&self, key: &Key) -> std::io::Result<bool> {
-        self.client.request("store.mem_tree", key).await?;
-        self.client.response().await
+        self.request(conn, "store.head", ()).await?;
+        let head: Option<Commit<H>> = self.response(conn).await?;
+        head.ok_or_else(|| Error::new(ErrorKind::InvalidData, "Branch has no head after write"))
     }
 
-    /// Remove the value associated with a key
-    pub async fn remove(&self, key: &Key, info: Info) -> std::io::Result<()> {
-        self.client.request("store.remove", (key, info)).await?;
-        self.client.response().await
+    /// Like `call`, for writes that create a commit, see `read_commit`
+    async fn call_returning<H: Hash>(
+        &self,
+        command: impl AsRef<str>,
+        msg: impl Type,
+    ) -> std::io::Result<Commit<H>> {
+        self.check_alive()?;
+        let mut conn = self.stream.lock().await;
+        self.cycle(async {
+            self.request(&mut conn, command, msg).await?;
+            self.read_commit(&mut conn).await
+        })
+        .await
     }
-}
 
-impl<H: Hash> Commit<H> {
-    /// Create a new commit
-    pub async fn create<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
-        client: &Client<Socket, Contents, H>,
-        node: &H,
-        parents: impl Into<Vec<H>>,
-        info: Info,
+    /// Like `call_tree`, for writes that create a commit, see `read_commit`
+    async fn call_tree_returning<T: Type, H: Hash>(
+        &self,
+        command: impl AsRef<str>,
+        prefix: impl Type,
+        tree: &Tree<T, H>,
     ) -> std::io::Result<Commit<H>> {
-        let parents = parents.into();
-        client.request("commit.v", (info, parents, node)).await?;
-        client.response().await
+        self.check_alive()?;
+        let mut conn = self.stream.lock().await;
+        self.cycle(async {
+            self.request_tree(&mut conn, command, prefix, tree).await?;
+            self.read_commit(&mut conn).await
+        })
+        .await
     }
 
-    pub async fn of_hash<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
-        client: &Client<Socket, Contents, H>,
-        hash: &H,
-    ) -> std::io::Result<Option<Commit<H>>> {
-        client.request("commit.of_hash", hash).await?;
-        client.response().await
+    /// Like `call`, for requests that respond with an optional length-prefixed byte string. The
+    /// bytes are copied from the socket directly into `w` instead of being buffered in memory.
+    /// Returns `false` if the response was `None`.
+    async fn call_to_writer<W: AsyncWrite + Unpin>(
+        &self,
+        command: impl AsRef<str>,
+        msg: impl Type,
+        w: &mut W,
+    ) -> std::io::Result<bool> {
+        self.check_alive()?;
+        let mut conn = self.stream.lock().await;
+        self.cycle(async {
+            self.request(&mut conn, command, msg).await?;
+            self.read_status(&mut conn).await?;
+            let len = self.read_len(&mut conn).await?;
+
+            let mut header = vec![conn.read_u8().await.map_err(closed)?];
+            if header[0] == 0 {
+                self.bytes_received.fetch_add(1, Ordering::Relaxed);
+                return Ok(false);
+            }
+
+            // Read the varint length prefix one byte at a time, then stream the rest
+            loop {
+                let b = conn.read_u8().await.map_err(closed)?;
+                header.push(b);
+                if b < 128 {
+                    break;
+                }
+            }
+            let n = usize::decode_bin(&mut &header[1..])?;
+            if header.len() + n != len {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Response is not a length-prefixed byte string",
+                ));
+            }
+
+            self.wire_log
+                .line('<', || format!("streaming {} bytes to writer", n));
+            let copied = tokio::io::copy(&mut (&mut *conn).take(n as u64), w).await?;
+            self.bytes_received
+                .fetch_add((header.len() as u64) + copied, Ordering::Relaxed);
+            if copied as usize != n {
+                return Err(crate::Error::ConnectionClosed.into());
+            }
+            Ok(true)
+        })
+        .await
     }
 }
 
-impl<T: Type, H: Hash> Tree<T, H> {
-    /// Add value to tree
-    pub async fn add<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
-        &self,
-        client: &Client<Socket, Contents, H>,
-        key: &Key,
-        value: &T,
-    ) -> std::io::Result<Tree<T, H>> {
-        client.request("tree.add", (self, key, value)).await?;
-        client.response().await
+impl<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type, H: Hash> Client<Socket, Contents, H> {
+    fn from_stream(conn: Socket, buffers: Option<(usize, usize)>) -> Self {
+        let poisoned = Arc::new(AtomicBool::new(false));
+        let timeouts = Arc::new(Timeouts::default());
+        let conn = Deadline::new(conn, timeouts.clone(), poisoned.clone());
+        #[cfg(feature = "replay")]
+        let recorder = conn.recorder().clone();
+        Client {
+            conn: Arc::new(Connection {
+                stream: Mutex::new(match buffers {
+                    Some((read, write)) => BufStream::with_capacity(read, write, conn),
+                    None => BufStream::new(conn),
+                }),
+                bytes_sent: AtomicU64::new(0),
+                bytes_received: AtomicU64::new(0),
+                dead: AtomicBool::new(false),
+                poisoned,
+                timeouts,
+                wire_log: WireLog::default(),
+                flush_every_n: AtomicUsize::new(FLUSH_EVERY_N),
+                generation: AtomicU64::new(0),
+                key_limits: std::sync::Mutex::new(KeyLimits::default()),
+                #[cfg(feature = "replay")]
+                recorder,
+            }),
+            keepalive: std::sync::Mutex::new(None),
+            shutdown: Connection::shutdown_now,
+            endpoint: None,
+            _t: std::marker::PhantomData,
+        }
     }
 
-    /// Add tree to tree
-    pub async fn add_tree<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
-        &self,
-        client: &Client<Socket, Contents, H>,
-        key: &Key,
-        tree: &Tree<T, H>,
-    ) -> std::io::Result<Tree<T, H>> {
-        client.request("tree.add_tree", (self, key, tree)).await?;
-        client.response().await
+    /// Hash of the protocol version, exchanged with the server during the handshake
+    fn handshake_hash() -> String {
+        format!("{:x}", blake2::Blake2b::digest(V1.as_bytes()))
     }
 
-    /// Remove key from tree
-    pub async fn remove<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
-        &self,
-        client: &Client<Socket, Contents, H>,
-        key: &Key,
-    ) -> std::io::Result<Tree<T, H>> {
-        client.request("tree.remove", (self, key)).await?;
-        client.response().await
+    async fn write_handshake(&self, conn: &mut BufStream<Deadline<Socket>>) -> std::io::Result<()> {
+        let hash = format!("{}\n", Self::handshake_hash());
+        self.conn.wire_log.line('>', || hash.clone());
+        conn.write_all(hash.as_bytes()).await?;
+        conn.flush().await?;
+        Ok(())
     }
 
-    /// Find value in tree
-    pub async fn find<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
+    async fn read_handshake(
         &self,
-        client: &Client<Socket, Contents, H>,
-        key: &Key,
-    ) -> std::io::Result<Option<T>> {
-        client.request("tree.find", (self, key)).await?;
-        client.response().await
+        conn: &mut BufStream<Deadline<Socket>>,
+    ) -> std::io::Result<String> {
+        let mut line = String::new();
+        conn.read_line(&mut line).await?;
+        self.conn.wire_log.line('<', || line.clone());
+        Ok(line)
     }
 
-    /// Find tree in tree
-    pub async fn find_tree<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
-        &self,
-        client: &Client<Socket, Contents, H>,
-        key: &Key,
-    ) -> std::io::Result<Option<Tree<T, H>>> {
-        client.request("tree.find_tree", (self, key)).await?;
-        client.response().await
+    async fn do_handshake(&self) -> std::io::Result<()> {
+        let mut conn = self.conn.stream.lock().await;
+        self.write_handshake(&mut conn).await?;
+        let line = self.read_handshake(&mut conn).await?;
+        let expected = Self::handshake_hash();
+        let actual = line.trim_end_matches('\n');
+        if actual != expected {
+            let actual = if line.is_empty() {
+                String::from("connection closed")
+            } else {
+                format!("{:?}", actual)
+            };
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                format!(
+                    "Invalid handshake: expected {:?} for protocol {}, got {}",
+                    expected, V1, actual
+                ),
+            ));
+        }
+        Ok(())
     }
 
-    /// Check if tree key is a value
-    pub async fn mem<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
-        &self,
-        client: &Client<Socket, Contents, H>,
-        key: &Key,
-    ) -> std::io::Result<bool> {
-        client.request("tree.mem", (self, key)).await?;
-        client.response().await
+    async fn call<T: Type>(&self, command: impl AsRef<str>, msg: impl Type) -> std::io::Result<T> {
+        self.conn.call(command, msg).await
     }
 
-    /// Check if tree key is a tree
-    pub async fn mem_tree<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
-        &self,
-        client: &Client<Socket, Contents, H>,
-        key: &Key,
-    ) -> std::io::Result<bool> {
-        client.request("tree.mem_tree", (self, key)).await?;
-        client.response().await
+    /// Create a `ClientBuilder` to configure a client before connecting
+    pub fn builder() -> ClientBuilder<Socket, Contents, H> {
+        ClientBuilder::new()
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::Bytes;
-    use crate::{client::*, *};
+    /// Close the client, flushing any buffered requests. Clients dropped without being closed
+    /// only make a single attempt to flush and shut down the socket that doesn't wait, so
+    /// requests that haven't been written may be lost.
+    pub async fn close(self) -> std::io::Result<()> {
+        if let Some(task) = self.keepalive.lock().unwrap().take() {
+            task.abort();
+        }
+        self.conn.dead.store(true, Ordering::Relaxed);
+        let mut conn = self.conn.stream.lock().await;
+        conn.flush().await?;
+        conn.shutdown().await?;
+        Ok(())
+    }
 
-    fn skip() -> std::io::Result<()> {
-        eprintln!("Skipping client test: client not connected, perhaps the server isn't running?");
-        return Ok(());
+    /// Write any buffered requests to the socket
+    pub async fn flush(&self) -> std::io::Result<()> {
+        self.conn.stream.lock().await.flush().await
     }
 
-    #[tokio::test]
-    async fn test_client() -> std::io::Result<()> {
-        let client = match Client::<Tcp, Bytes, Blake2b>::new("127.0.0.1:9181").await {
-            Ok(c) => c,
-            Err(e) => {
+    /// Ping the server, used to check to ensure the client is connected
+    pub async fn ping(&self) -> std::io::Result<()> {
+        self.call::<()>("ping", ()).await?;
+        Ok(())
+    }
+
+    /// Start a background task that pings the server every `interval` while the client is idle.
+    /// If a ping fails the connection is marked as dead and any further requests fail with
+    /// `ErrorKind::NotConnected`. Calling this again replaces the previous keepalive task.
+    pub fn enable_keepalive(&self, interval: Duration)
+    where
+        Socket: Send + 'static,
+    {
+        let conn = Arc::downgrade(&self.conn);
+        let task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let conn = match conn.upgrade() {
+                    Some(conn) => conn,
+                    None => break,
+                };
+                if conn.call::<()>("ping", ()).await.is_err() {
+                    conn.dead.store(true, Ordering::Relaxed);
+                    break;
+                }
+            }
+        });
+
+        if let Some(prev) = self.keepalive.lock().unwrap().replace(task) {
+            prev.abort();
+        }
+    }
+
+    /// Write a hex/ASCII dump of every message sent and received to `w`, along with the
+    /// command names and handshake. This is only meant for debugging, when it isn't enabled the
+    /// only cost is checking if a writer has been set.
+    pub fn with_wire_log(self, w: impl std::io::Write + Send + 'static) -> Self {
+        self.conn.wire_log.set(Box::new(w));
+        self
+    }
+
+    /// Write a `Record` of every request sent and the response it received to `w`, the records
+    /// can be replayed without a server using `Client::replay`. The handshake isn't recorded.
+    #[cfg(feature = "replay")]
+    pub fn with_recorder(self, w: impl std::io::Write + Send + 'static) -> Self {
+        self.conn.recorder.set(Box::new(w));
+        self
+    }
+
+    /// Fail any single read from the socket that makes no progress for longer than `timeout`,
+    /// `None` disables the timeout. This bounds stalls on individual reads, separately from how
+    /// long a whole request takes. After a timeout the client can no longer be used.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.conn.timeouts.set_read(timeout)
+    }
+
+    /// Like `set_read_timeout`, for writes to the socket
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.conn.timeouts.set_write(timeout)
+    }
+
+    /// Get the timeout set using `set_read_timeout`
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.conn.timeouts.read()
+    }
+
+    /// Get the timeout set using `set_write_timeout`
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.conn.timeouts.write()
+    }
+
+    /// Set the number of requests a batch (`find_many`, `Tree::to_concrete`) writes before
+    /// flushing and reading their responses, `0` writes the whole batch first. Smaller values
+    /// bound how much the server has to buffer, larger values mean fewer round trips. The
+    /// default is 64, see `benches/pipeline.rs` for the measurements it's based on.
+    pub fn set_flush_every_n(&self, n: usize) {
+        self.conn.flush_every_n.store(n, Ordering::Relaxed)
+    }
+
+    /// Get the value set using `set_flush_every_n`
+    pub fn flush_every_n(&self) -> usize {
+        self.conn.flush_every_n.load(Ordering::Relaxed)
+    }
+
+    /// Set the limits used when decoding keys in responses, this only applies to this client
+    /// and any clones of it. Keys over the limits fail to decode with `Error::Decode`.
+    pub fn set_key_limits(&self, limits: KeyLimits) {
+        *self.conn.key_limits.lock().unwrap() = limits;
+    }
+
+    /// Get the limits set using `set_key_limits`
+    pub fn key_limits(&self) -> KeyLimits {
+        self.conn.key_limits()
+    }
+
+    /// Get the number of bytes sent and received in messages since the client was created or
+    /// `reset_stats` was last called
+    pub fn stats(&self) -> Stats {
+        Stats {
+            bytes_sent: self.conn.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.conn.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reset the transfer counters returned by `stats`
+    pub fn reset_stats(&self) {
+        self.conn.bytes_sent.store(0, Ordering::Relaxed);
+        self.conn.bytes_received.store(0, Ordering::Relaxed);
+    }
+
+    /// Get the irmin version, hash algorithm and contents type name used by the server. Returns
+    /// an `ErrorKind::Unsupported` error if the server doesn't support introspection.
+    pub async fn server_info(&self) -> std::io::Result<ServerInfo> {
+        self.call("server_info", ())
+            .await
+            .map_err(|e| unsupported("server_info", e))
+    }
+
+    /// Access store methods
+    pub fn store<'a>(&'a self) -> Store<'a, Socket, Contents, H> {
+        Store {
+            client: self,
+            info: None,
+        }
+    }
+
+    /// Set the client's branch
+    pub async fn set_current_branch(&self, branch: impl AsRef<str>) -> std::io::Result<()> {
+        let res = self.call::<()>("set_current_branch", branch.as_ref()).await;
+        self.conn.generation.fetch_add(1, Ordering::Relaxed);
+        res
+    }
+
+    /// Get the client's branch
+    pub async fn get_current_branch(&self) -> std::io::Result<String> {
+        self.call("get_current_branch", ()).await
+    }
+}
+
+impl<Socket, Contents: Type, H: Hash> Client<Socket, Contents, H> {
+    /// Check if the client can still be used to send requests. This is `false` once the client
+    /// has been closed, a keepalive ping has failed or the connection has been poisoned.
+    pub fn is_connected(&self) -> bool {
+        !self.conn.dead.load(Ordering::Relaxed) && !self.is_poisoned()
+    }
+
+    /// Check if a timeout, or a request future that was dropped before it completed, left part
+    /// of a request or response on the socket. Since the next response can't be matched to its
+    /// request, every request made after that fails with `ErrorKind::NotConnected`.
+    pub fn is_poisoned(&self) -> bool {
+        self.conn.poisoned.load(Ordering::Relaxed)
+    }
+
+    /// Drop a client that is intentionally being thrown away without calling `close`, such as
+    /// excess idle connections in a `Pool`
+    pub(crate) fn discard(self) {
+        self.conn.dead.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<Socket, Contents: Type, H: Hash> Drop for Client<Socket, Contents, H> {
+    fn drop(&mut self) {
+        let keepalive = match self.keepalive.get_mut() {
+            Ok(keepalive) => keepalive.take(),
+            Err(e) => e.into_inner().take(),
+        };
+        if let Some(task) = keepalive {
+            task.abort();
+        }
+
+        // Dropping the socket closes it, but anything still buffered would be lost and the server
+        // would only see the connection reset. `close` can't be called here since it needs to
+        // wait for the connection lock, so make a single attempt that doesn't block instead.
+        if !self.conn.dead.swap(true, Ordering::Relaxed) {
+            (self.shutdown)(&self.conn);
+        }
+    }
+}
+
+impl<C: Type, H: Hash> Client<TcpStream, C, H> {
+    /// Create a new client connected to a TCP server
+    pub async fn new(s: impl ToSocketAddrs) -> std::io::Result<Client<TcpStream, C, H>> {
+        Self::builder().connect(s).await
+    }
+
+    /// Try to connect to each address in order, returning the first client that completes the
+    /// handshake. If none succeed the returned error lists the failure for every address.
+    pub async fn connect_any(addrs: &[SocketAddr]) -> std::io::Result<Client<TcpStream, C, H>> {
+        let mut errors = Vec::new();
+        let mut kind = ErrorKind::InvalidInput;
+        for addr in addrs {
+            match Self::new(addr).await {
+                Ok(client) => return Ok(client),
+                Err(e) => {
+                    kind = e.kind();
+                    errors.push(format!("{}: {}", addr, e));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            return Err(Error::new(kind, "No addresses to connect to"));
+        }
+
+        Err(Error::new(
+            kind,
+            format!("Unable to connect to any address ({})", errors.join(", ")),
+        ))
+    }
+
+    /// Get the address of the server the client is connected to
+    pub fn endpoint(&self) -> Option<SocketAddr> {
+        self.endpoint
+    }
+}
+
+impl<C: Type, H: Hash> Client<UnixStream, C, H> {
+    /// Create a new client connected to a Unix socket
+    pub async fn new(s: impl AsRef<std::path::Path>) -> std::io::Result<Client<UnixStream, C, H>> {
+        Self::builder().connect(s).await
+    }
+
+    /// Create a new client connected to a Unix socket in the Linux abstract namespace, `name`
+    /// should not include the leading null byte
+    #[cfg(target_os = "linux")]
+    pub async fn new_abstract(name: impl AsRef<[u8]>) -> std::io::Result<Client<UnixStream, C, H>> {
+        Self::builder().connect_abstract(name).await
+    }
+}
+
+impl<'a, Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type, H: Hash>
+    Store<'a, Socket, Contents, H>
+{
+    /// Get a counter that is incremented each time the current branch may have been changed
+    /// using this client: by every write, including `set`, `set_tree`, `remove`, the merges
+    /// (even `merge_into`, since `into` may be the current branch) and `import`, or by switching
+    /// branches. Caches can compare it to tell whether their entries may be stale without a
+    /// request to the server. Changes made by other clients are not counted, so it can't replace
+    /// checking the branch head when other writers exist.
+    pub fn generation(&self) -> u64 {
+        self.client.conn.generation.load(Ordering::Relaxed)
+    }
+
+    /// Wait for a request that changes the current branch, then increment the generation. This
+    /// happens even when the request fails, since it may have been applied anyway.
+    async fn write<R>(
+        &self,
+        req: impl std::future::Future<Output = std::io::Result<R>>,
+    ) -> std::io::Result<R> {
+        let res = req.await;
+        self.client.conn.generation.fetch_add(1, Ordering::Relaxed);
+        res
+    }
+
+    /// Set the `Info` used by `put` and `delete`, the date is replaced with the current time
+    /// each time it's used
+    pub fn with_info(mut self, info: Info) -> Self {
+        self.info = Some(info);
+        self
+    }
+
+    /// Get the `Info` used by `put` and `delete`, `Info::new()` if none was set using
+    /// `with_info`
+    pub fn info(&self) -> Info {
+        match &self.info {
+            Some(info) => {
+                let mut info = info.clone();
+                info.date = Info::new().date;
+                info
+            }
+            None => Info::new(),
+        }
+    }
+
+    /// Like `set`, using the `Info` returned by `info`
+    pub async fn put<T: Type>(&self, key: &Key, value: T) -> std::io::Result<()> {
+        self.set(key, value, self.info()).await
+    }
+
+    /// Like `remove`, using the `Info` returned by `info`
+    pub async fn delete(&self, key: &Key) -> std::io::Result<()> {
+        self.remove(key, self.info()).await
+    }
+
+    /// Set the value associated with a key
+    pub async fn set<T: Type>(&self, key: &Key, value: T, info: Info) -> std::io::Result<()> {
+        self.write(self.client.call("store.set", (key, info, value)))
+            .await
+    }
+
+    /// Like `set`, returning the commit that was created. Servers that acknowledge `store.set`
+    /// with the new head only need a single request. Otherwise the head is requested before any
+    /// other request is sent on this connection, although it can still include writes made by
+    /// other clients.
+    pub async fn set_returning<T: Type>(
+        &self,
+        key: &Key,
+        value: T,
+        info: Info,
+    ) -> std::io::Result<Commit<H>> {
+        self.write(
+            self.client
+                .conn
+                .call_returning("store.set", (key, info, value)),
+        )
+        .await
+    }
+
+    /// Set the value associated with a key only if it differs from the current value, returns
+    /// `true` if a commit was created. The values are compared by hash, so the current value is
+    /// never downloaded. The hash of the new value is computed by the server, so when the key is
+    /// set to a value the new value is stored using `add_blob` first, and sent a second time if
+    /// it differs. The stored blob is the one the new commit refers to, but it's left in the
+    /// content store without being referenced by any commit if the `set` fails. Servers that
+    /// don't support `stat` are sent a `find` instead, so the current value is downloaded and
+    /// compared by its encoding. This isn't atomic: a concurrent write between the comparison and the commit may
+    /// be overwritten.
+    pub async fn set_if_changed<T: Type>(
+        &self,
+        key: &Key,
+        value: T,
+        info: Info,
+    ) -> std::io::Result<bool> {
+        let encode = |x: &T| -> std::io::Result<Vec<u8>> {
+            let mut data = Vec::new();
+            x.encode_bin(&mut data)?;
+            Ok(data)
+        };
+
+        let unchanged = match self.stat(key).await {
+            Ok(Some(stat)) => {
+                stat.kind == Kind::Contents && self.add_blob(&value).await? == stat.hash
+            }
+            Ok(None) => false,
+            Err(e) if e.kind() == ErrorKind::Unsupported => match self.find::<T>(key).await? {
+                Some(current) => encode(&current)? == encode(&value)?,
+                None => false,
+            },
+            Err(e) => return Err(e),
+        };
+        if unchanged {
+            return Ok(false);
+        }
+        self.set(key, value, info).await?;
+        Ok(true)
+    }
+
+    /// Set the value associated with a key only if its current value is `test`, where `None`
+    /// means the key isn't set. Passing `None` for `set` removes the key. Returns `false` without
+    /// creating a commit if the current value doesn't match `test`.
+    pub async fn test_and_set<T: Type>(
+        &self,
+        key: &Key,
+        test: Option<&T>,
+        set: Option<&T>,
+        info: Info,
+    ) -> std::io::Result<bool> {
+        self.write(
+            self.client
+                .call("store.test_and_set", (key, info, (test, set))),
+        )
+        .await
+    }
+
+    /// Set the tree associated with a key only if the current tree is `test`, where `None`
+    /// means the key isn't set. Passing `None` for `set` removes the key. Returns `false` without
+    /// creating a commit if the current tree doesn't match `test`, or an
+    /// `ErrorKind::Unsupported` error if the server doesn't support `store.test_and_set_tree`.
+    pub async fn test_and_set_tree<T: Type>(
+        &self,
+        key: &Key,
+        test: Option<&Tree<T, H>>,
+        set: Option<&Tree<T, H>>,
+        info: Info,
+    ) -> std::io::Result<bool> {
+        self.write(
+            self.client
+                .call("store.test_and_set_tree", (key, info, (test, set))),
+        )
+        .await
+        .map_err(|e| unsupported("store.test_and_set_tree", e))
+    }
+
+    /// Replace the value associated with a key by the result of applying `f` to the current
+    /// value, using `test_and_set` so concurrent writes are never overwritten. If the value
+    /// changes between reading it and committing, it is read again and `f` is re-applied, up to
+    /// 8 retries, see `update_with_retries`. Returns the new value.
+    pub async fn update_with<T: Type, F: FnMut(Option<&T>) -> Option<T>>(
+        &self,
+        key: &Key,
+        f: F,
+        info: Info,
+    ) -> std::io::Result<Option<T>> {
+        self.update_with_retries(key, UPDATE_RETRIES, f, info).await
+    }
+
+    /// Like `update_with`, retrying at most `retries` times. If every attempt conflicts the
+    /// returned error carries an `Error::Conflict`.
+    pub async fn update_with_retries<T: Type, F: FnMut(Option<&T>) -> Option<T>>(
+        &self,
+        key: &Key,
+        retries: usize,
+        mut f: F,
+        info: Info,
+    ) -> std::io::Result<Option<T>> {
+        for _ in 0..=retries {
+            let current = self.find::<T>(key).await?;
+            let new = f(current.as_ref());
+            if self
+                .test_and_set(key, current.as_ref(), new.as_ref(), info.clone())
+                .await?
+            {
+                return Ok(new);
+            }
+        }
+        Err(crate::Error::Conflict {
+            attempts: retries + 1,
+        }
+        .into())
+    }
+
+    /// Set the tree associated with a key.
+    ///
+    /// Concrete trees that encode to more than 16MiB are built on the server in parts, so the
+    /// server never has to read the whole tree as a single message. Each subtree small enough to
+    /// fit is added with `tree.add_tree`, larger subtrees are split into their children, then the
+    /// resulting tree is set in one final request. Every part takes a round trip, so this is only
+    /// used when a single message would be too large. A single contents value over the limit is
+    /// still sent in one message.
+    pub async fn set_tree<T: Type>(
+        &self,
+        key: &Key,
+        tree: &Tree<T, H>,
+        info: Info,
+    ) -> std::io::Result<()> {
+        self.set_tree_in_parts(key, tree, info, LARGE_TREE_SIZE)
+            .await
+    }
+
+    /// Like `set_tree`, returning the commit that was created, see `set_returning`
+    pub async fn set_tree_returning<T: Type>(
+        &self,
+        key: &Key,
+        tree: &Tree<T, H>,
+        info: Info,
+    ) -> std::io::Result<Commit<H>> {
+        let parts = self.upload_parts(tree, LARGE_TREE_SIZE).await?;
+        self.write(self.client.conn.call_tree_returning(
+            "store.set_tree",
+            (key, info),
+            parts.as_ref().unwrap_or(tree),
+        ))
+        .await
+    }
+
+    async fn set_tree_in_parts<T: Type>(
+        &self,
+        key: &Key,
+        tree: &Tree<T, H>,
+        info: Info,
+        limit: usize,
+    ) -> std::io::Result<()> {
+        let parts = self.upload_parts(tree, limit).await?;
+        self.write(self.client.conn.call_tree(
+            "store.set_tree",
+            (key, info),
+            parts.as_ref().unwrap_or(tree),
+        ))
+        .await
+    }
+
+    /// Build a concrete tree that encodes to more than `limit` bytes on the server in parts,
+    /// see `set_tree`. Returns `None` if the tree can be sent as it is.
+    async fn upload_parts<T: Type>(
+        &self,
+        tree: &Tree<T, H>,
+        limit: usize,
+    ) -> std::io::Result<Option<Tree<T, H>>> {
+        let encoded_size = |c: &Concrete<T>| -> std::io::Result<usize> {
+            let mut counter = Counter(0);
+            c.encode_bin(&mut counter)?;
+            Ok(counter.0)
+        };
+
+        let c = match tree {
+            Tree::Concrete(c) if c.is_tree() && encoded_size(c)? > limit => c,
+            _ => return Ok(None),
+        };
+
+        let mut parts = Tree::<T, H>::empty();
+        let mut stack = vec![(Key::empty(), c)];
+        while let Some((prefix, node)) = stack.pop() {
+            match node {
+                Concrete::Tree(t) if encoded_size(node)? > limit => {
+                    for (step, child) in t.iter().rev() {
+                        let mut key = prefix.clone();
+                        key.push(step.clone());
+                        stack.push((key, child));
+                    }
+                }
+                _ => {
+                    parts = self
+                        .client
+                        .call("tree.add_tree", (&parts, &prefix, Subtree(node)))
+                        .await?;
+                }
+            }
+        }
+        Ok(Some(parts))
+    }
+
+    /// Set the tree associated with a key only if the current branch still points at
+    /// `expected_head`, where `None` means the branch has no commits yet. Returns `false`
+    /// without creating a commit if another writer has moved the branch. The check and the
+    /// update are done by the server as a single test-and-set of the branch, so this returns an
+    /// `ErrorKind::Unsupported` error if the server doesn't support `store.set_tree_checked`.
+    pub async fn set_tree_checked<T: Type>(
+        &self,
+        key: &Key,
+        tree: &Tree<T, H>,
+        expected_head: Option<&Commit<H>>,
+        info: Info,
+    ) -> std::io::Result<bool> {
+        let prefix = (key, info, expected_head);
+        self.write(
+            self.client
+                .conn
+                .call_tree("store.set_tree_checked", prefix, tree),
+        )
+        .await
+        .map_err(|e| unsupported("store.set_tree_checked", e))
+    }
+
+    /// Find a value in the store
+    pub async fn find<T: Type>(&self, key: &Key) -> std::io::Result<Option<T>> {
+        self.client.call("store.find", key).await
+    }
+
+    /// Find a value in the store along with its contents hash, using a single request. Servers
+    /// that don't support this are sent a `find` followed by a `stat`, which fails with
+    /// `ErrorKind::Unsupported` if `stat` isn't supported either. Returns `None` if the key is
+    /// not set to a value.
+    pub async fn find_with_hash<T: Type>(&self, key: &Key) -> std::io::Result<Option<(T, H)>> {
+        match self.client.call("store.find_with_hash", key).await {
+            Err(e) if is_unknown_command(&e) => (),
+            res => return res,
+        }
+
+        let value = match self.find::<T>(key).await? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        match self.stat(key).await? {
+            Some(stat) if stat.kind == Kind::Contents => Ok(Some((value, stat.hash))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Find a value in the store along with its metadata. Returns an `ErrorKind::Unsupported`
+    /// error if the server doesn't support `store.find_all`.
+    pub async fn find_all<T: Type, M: Type>(&self, key: &Key) -> std::io::Result<Option<(T, M)>> {
+        self.client
+            .call("store.find_all", key)
+            .await
+            .map_err(|e| unsupported("store.find_all", e))
+    }
+
+    /// Set the value associated with a key along with its metadata, unlike `set` this preserves
+    /// metadata read using `find_all`. Returns an `ErrorKind::Unsupported` error if the server
+    /// doesn't support `store.set_all`.
+    pub async fn set_all<T: Type, M: Type>(
+        &self,
+        key: &Key,
+        value: T,
+        metadata: M,
+        info: Info,
+    ) -> std::io::Result<()> {
+        self.write(
+            self.client
+                .call("store.set_all", (key, info, (value, metadata))),
+        )
+        .await
+        .map_err(|e| unsupported("store.set_all", e))
+    }
+
+    /// Set the values for many keys, pipelining the requests like `find_many`. Each value is
+    /// committed separately, use `set_tree` with a tree built using `TreeBuilder` to write them
+    /// in a single commit
+    pub async fn set_many<T: Type>(&self, items: &[(Key, T)], info: Info) -> std::io::Result<()> {
+        let msgs = items.iter().map(|(key, value)| (key, info.clone(), value));
+        let _: Vec<()> = self
+            .write(self.client.conn.call_many("store.set", msgs))
+            .await?;
+        Ok(())
+    }
+
+    /// Find the values for many keys at once, the results are in the same order as `keys`
+    pub async fn find_many<T: Type>(&self, keys: &[Key]) -> std::io::Result<Vec<Option<T>>> {
+        self.client.conn.call_many("store.find", keys).await
+    }
+
+    /// Find a value in the store and copy it into `w` as it is received, without buffering the
+    /// whole value in memory. Returns `false` if the key is not set. The contents must be encoded
+    /// as a length-prefixed byte string, like `Bytes` or `String`.
+    pub async fn find_to_writer(
+        &self,
+        key: &Key,
+        w: &mut (impl AsyncWrite + Unpin),
+    ) -> std::io::Result<bool> {
+        let found = self
+            .client
+            .conn
+            .call_to_writer("store.find", key, w)
+            .await?;
+        w.flush().await?;
+        Ok(found)
+    }
+
+    /// Find a tree in the store
+    pub async fn find_tree<T: Type>(&self, key: &Key) -> std::io::Result<Option<Tree<T, H>>> {
+        self.client.call("store.find_tree", key).await
+    }
+
+    /// Get the root tree of the current branch head, an empty tree is returned if the branch has
+    /// no commits. Servers that don't support `store.tree` are sent a `find_tree` for the empty
+    /// key instead.
+    pub async fn tree<T: Type>(&self) -> std::io::Result<Tree<T, H>> {
+        let tree = match self.client.call("store.tree", ()).await {
+            Err(e) if is_unknown_command(&e) => self.find_tree(&Key::empty()).await?,
+            res => res?,
+        };
+        Ok(tree.unwrap_or_else(Tree::empty))
+    }
+
+    /// Like `find_tree`, also returning the steps directly under the tree along with the kind of
+    /// node each one points to, using a single request. Servers that don't support this are sent
+    /// a `find_tree` followed by a `Tree::list`.
+    pub async fn find_tree_with_children<T: Type>(
+        &self,
+        key: &Key,
+    ) -> std::io::Result<Option<(Tree<T, H>, Vec<(String, Kind)>)>> {
+        match self.client.call("store.find_tree_with_children", key).await {
+            Err(e) if is_unknown_command(&e) => (),
+            res => return res,
+        }
+
+        let tree = match self.find_tree::<T>(key).await? {
+            Some(tree) => tree,
+            None => return Ok(None),
+        };
+        let children = tree.list(self.client, &Key::empty()).await?;
+        Ok(Some((tree, children)))
+    }
+
+    /// List the children of a key one page at a time, see `ListStream`. Listing a key that isn't
+    /// set to a tree returns no children.
+    pub fn list_stream(&self, key: &Key) -> ListStream<'a, Socket, Contents, H> {
+        let store = Store {
+            client: self.client,
+            info: self.info.clone(),
+        };
+        ListStream::new(store, key)
+    }
+
+    /// Collect the key of every contents node under a key without fetching any contents. When
+    /// `max_depth` is set only nodes at most that many steps below `key` are returned, trees
+    /// deeper than that are not listed. Listing a key that isn't set to a tree returns no keys.
+    pub async fn list_recursive(
+        &self,
+        key: &Key,
+        max_depth: Option<usize>,
+    ) -> std::io::Result<Vec<Key>> {
+        let tree = match self.find_tree::<Contents>(key).await? {
+            Some(tree) => tree,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut keys = Vec::new();
+        let mut stack = vec![(Key::empty(), 0)];
+        while let Some((path, depth)) = stack.pop() {
+            if Some(depth) == max_depth {
+                continue;
+            }
+            for (step, kind) in tree.list(self.client, &path).await? {
+                let mut child = path.clone();
+                child.push(step);
+                match kind {
+                    Kind::Contents => {
+                        let mut full = key.clone();
+                        for step in child.steps() {
+                            full.push(step.as_str());
+                        }
+                        keys.push(full)
+                    }
+                    Kind::Tree => stack.push((child, depth + 1)),
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Check if a key is set to a value, returns `false` for keys that are set to a tree. Use
+    /// `exists` to check for either.
+    pub async fn mem<T: Type>(&self, key: &Key) -> std::io::Result<bool> {
+        self.client.call("store.mem", key).await
+    }
+
+    /// Check if a key is set to a tree, returns `false` for keys that are set to a value
+    pub async fn mem_tree<T: Type>(&self, key: &Key) -> std::io::Result<bool> {
+        self.client.call("store.mem_tree", key).await
+    }
+
+    /// Check if a key is set to either a value or a tree, using a single request. Servers that
+    /// don't support `stat` are sent a `mem` followed by a `mem_tree` if needed.
+    pub async fn exists(&self, key: &Key) -> std::io::Result<bool> {
+        match self.stat(key).await {
+            Err(e) if e.kind() == ErrorKind::Unsupported => (),
+            res => return Ok(res?.is_some()),
+        }
+        Ok(self.mem::<Contents>(key).await? || self.mem_tree::<Contents>(key).await?)
+    }
+
+    /// Get the kind and hash of the node at a key in a single request, returns `None` if the key
+    /// is not set. Returns an `ErrorKind::Unsupported` error if the server doesn't support
+    /// `store.stat`, the hash can't be found using the other commands.
+    pub async fn stat(&self, key: &Key) -> std::io::Result<Option<NodeStat<H>>> {
+        self.client
+            .call("store.stat", key)
+            .await
+            .map_err(|e| unsupported("store.stat", e))
+    }
+
+    /// Get the hash of the tree at a key without transferring the tree, returns `None` if the
+    /// key is not set to a tree. Like `stat` this requires server support, otherwise an
+    /// `ErrorKind::Unsupported` error is returned.
+    pub async fn tree_hash(&self, key: &Key) -> std::io::Result<Option<H>> {
+        let stat = self.stat(key).await?;
+        Ok(stat.filter(|s| s.kind == Kind::Tree).map(|s| s.hash))
+    }
+
+    /// Remove the value associated with a key
+    pub async fn remove(&self, key: &Key, info: Info) -> std::io::Result<()> {
+        self.write(self.client.call("store.remove", (key, info)))
+            .await
+    }
+
+    /// Like `remove`, returning the head commit afterwards, see `set_returning`. If the key
+    /// wasn't set, no commit is created and the current head is returned.
+    pub async fn remove_returning(&self, key: &Key, info: Info) -> std::io::Result<Commit<H>> {
+        self.write(self.client.conn.call_returning("store.remove", (key, info)))
+            .await
+    }
+
+    /// Remove many keys in a single commit. Keys that aren't set are skipped, nothing is committed
+    /// if none of them are set. The new root is built from the current one and committed using
+    /// `test_and_set_tree`, so if another writer changes the root in between it is read again and
+    /// the keys are removed again, up to 8 retries. If every attempt conflicts the returned error
+    /// carries an `Error::Conflict`.
+    pub async fn remove_batch(&self, keys: &[Key], info: Info) -> std::io::Result<()> {
+        let root = Key::empty();
+        for _ in 0..=UPDATE_RETRIES {
+            let old = match self.find_tree::<Contents>(&root).await? {
+                Some(tree) => tree,
+                None => return Ok(()),
+            };
+            let mut new = None;
+            for key in keys {
+                let tree = new.as_ref().unwrap_or(&old);
+                if tree.mem(self.client, key).await? || tree.mem_tree(self.client, key).await? {
+                    new = Some(tree.remove(self.client, key).await?);
+                }
+            }
+            let new = match new {
+                Some(tree) => tree,
+                None => return Ok(()),
+            };
+            if self
+                .test_and_set_tree(&root, Some(&old), Some(&new), info.clone())
+                .await?
+            {
+                return Ok(());
+            }
+        }
+        Err(crate::Error::Conflict {
+            attempts: UPDATE_RETRIES + 1,
+        }
+        .into())
+    }
+
+    /// Get the head commit of the current branch, returns `None` if the branch is empty
+    pub async fn head(&self) -> std::io::Result<Option<Commit<H>>> {
+        self.client.call("store.head", ()).await
+    }
+
+    /// Get the head commit of `branch` without changing the current branch, returns `None` if
+    /// the branch doesn't exist or is empty. Returns an `ErrorKind::Unsupported` error if the
+    /// server doesn't support `branch.find`.
+    pub async fn branch_head(&self, branch: impl AsRef<str>) -> std::io::Result<Option<Commit<H>>> {
+        self.client
+            .call("branch.find", branch.as_ref())
+            .await
+            .map_err(|e| unsupported("branch.find", e))
+    }
+
+    /// List the names of every branch. Returns an `ErrorKind::Unsupported` error if the server
+    /// doesn't support `branch.list`.
+    pub async fn branches(&self) -> std::io::Result<Vec<String>> {
+        self.client
+            .call("branch.list", ())
+            .await
+            .map_err(|e| unsupported("branch.list", e))
+    }
+
+    /// Get every branch along with its head commit, empty branches are skipped. The heads are
+    /// requested using a single pipelined batch, so this takes two round trips however many
+    /// branches there are. A branch removed between listing and reading its head is skipped.
+    /// Like `branches` and `branch_head` this requires server support, otherwise an
+    /// `ErrorKind::Unsupported` error is returned.
+    pub async fn branch_heads(&self) -> std::io::Result<Vec<(String, Commit<H>)>> {
+        let branches = self.branches().await?;
+        let heads: Vec<Option<Commit<H>>> = self
+            .client
+            .conn
+            .call_many("branch.find", branches.iter().map(|b| b.as_str()))
+            .await
+            .map_err(|e| unsupported("branch.find", e))?;
+        Ok(branches
+            .into_iter()
+            .zip(heads)
+            .filter_map(|(branch, head)| Some((branch, head?)))
+            .collect())
+    }
+
+    /// Switch to `branch`, run `f` and switch back to the previous branch, even when `f` returns
+    /// an error or panics. The current branch belongs to the connection, so other tasks using
+    /// the same `Client` will also see `branch` while `f` is running.
+    pub async fn with_branch<F, Fut, R>(&self, branch: impl AsRef<str>, f: F) -> std::io::Result<R>
+    where
+        F: FnOnce(Store<'a, Socket, Contents, H>) -> Fut,
+        Fut: std::future::Future<Output = std::io::Result<R>>,
+    {
+        let prev = self.client.get_current_branch().await?;
+        self.client.set_current_branch(branch).await?;
+
+        let store = Store {
+            client: self.client,
+            info: self.info.clone(),
+        };
+        let mut fut = Box::pin(f(store));
+        let res = std::future::poll_fn(|cx| {
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| fut.as_mut().poll(cx)))
+                .map_or_else(|e| std::task::Poll::Ready(Err(e)), |p| p.map(Ok))
+        })
+        .await;
+
+        let restored = self.client.set_current_branch(prev).await;
+        match res {
+            Ok(res) => {
+                let x = res?;
+                restored?;
+                Ok(x)
+            }
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+
+    /// Get the info of the head commit of the current branch, returns `None` if the branch is
+    /// empty
+    pub async fn head_info(&self) -> std::io::Result<Option<Info>> {
+        Ok(self.head().await?.map(|commit| commit.info))
+    }
+
+    /// Add a value to the content store without associating it with a key, returning its hash
+    pub async fn add_blob<T: Type>(&self, value: &T) -> std::io::Result<H> {
+        self.client.call("contents.save", value).await
+    }
+
+    /// Find a value in the content store by hash
+    pub async fn find_blob<T: Type>(&self, hash: &H) -> std::io::Result<Option<T>> {
+        self.client.call("contents.of_hash", hash).await
+    }
+
+    /// Merge the branch `from` into the current branch. A merge conflict is returned as
+    /// `Ok(Err(conflict))`, other errors are returned as `Err`
+    pub async fn merge(
+        &self,
+        from: impl AsRef<str>,
+        info: Info,
+    ) -> std::io::Result<std::result::Result<(), String>> {
+        self.write(self.client.call("store.merge", (info, from.as_ref())))
+            .await
+    }
+
+    /// Merge the branch `from` into the branch `into` without changing the current branch,
+    /// conflicts are reported the same way as `merge`
+    pub async fn merge_into(
+        &self,
+        from: impl AsRef<str>,
+        into: impl AsRef<str>,
+        info: Info,
+    ) -> std::io::Result<std::result::Result<(), String>> {
+        self.write(
+            self.client
+                .call("store.merge_into", (info, from.as_ref(), into.as_ref())),
+        )
+        .await
+    }
+
+    /// Three-way merge `tree` into the tree at `key` on the current branch, instead of replacing
+    /// it like `set_tree`. Conflicts are reported the same way as `merge`. Returns an
+    /// `ErrorKind::Unsupported` error if the server doesn't support `store.merge_tree`.
+    pub async fn merge_tree<T: Type>(
+        &self,
+        key: &Key,
+        tree: &Tree<T, H>,
+        info: Info,
+    ) -> std::io::Result<std::result::Result<(), String>> {
+        self.write(
+            self.client
+                .conn
+                .call_tree("store.merge_tree", (key, info), tree),
+        )
+        .await
+        .map_err(|e| unsupported("store.merge_tree", e))
+    }
+
+    /// Check if a commit with the given hash exists in the repo. Servers that don't support
+    /// `commit.mem` are sent a `commit.of_hash` instead, which transfers the commit.
+    pub async fn mem_commit(&self, hash: &H) -> std::io::Result<bool> {
+        match self.client.call("commit.mem", hash).await {
+            Err(e) if is_unknown_command(&e) => {
+                Ok(Commit::of_hash(self.client, hash).await?.is_some())
+            }
+            res => res,
+        }
+    }
+
+    /// Get the commits reachable from `new` but not from `old`, including `new` itself. Every
+    /// commit comes before its parents, so `new` is first.
+    pub async fn commits_between(
+        &self,
+        old: &Commit<H>,
+        new: &Commit<H>,
+    ) -> std::io::Result<Vec<Commit<H>>> {
+        if old == new {
+            return Ok(Vec::new());
+        }
+
+        let mut excluded = std::collections::HashSet::new();
+        let mut stack = old.parents.clone();
+        while let Some(hash) = stack.pop() {
+            if !excluded.insert(hash_id(&hash)?) {
+                continue;
+            }
+            match Commit::of_hash(self.client, &hash).await? {
+                Some(commit) if &commit == new => return Ok(Vec::new()),
+                Some(commit) => stack.extend(commit.parents),
+                None => (),
+            }
+        }
+
+        // Depth-first walk that adds each commit after all of its parents
+        let mut commits = Vec::new();
+        let mut stack = vec![(new.clone(), 0)];
+        while let Some((commit, index)) = stack.last_mut() {
+            let hash = match commit.parents.get(*index) {
+                Some(hash) => hash.clone(),
+                None => {
+                    commits.extend(stack.pop().map(|(commit, _)| commit));
+                    continue;
+                }
+            };
+            *index += 1;
+
+            if !excluded.insert(hash_id(&hash)?) {
+                continue;
+            }
+            match Commit::of_hash(self.client, &hash).await? {
+                Some(commit) if &commit != old => stack.push((commit, 0)),
+                _ => (),
+            }
+        }
+        commits.reverse();
+        Ok(commits)
+    }
+
+    /// Export the commits reachable from `to` but not from `from`, along with the trees and
+    /// contents they refer to. The result uses Irmin's slice encoding, so it can be imported
+    /// into another repo. Returns an `ErrorKind::Unsupported` error if the server doesn't support
+    /// `repo.export`.
+    pub async fn export_range(&self, from: &Commit<H>, to: &Commit<H>) -> std::io::Result<Vec<u8>> {
+        self.client
+            .conn
+            .call_raw("repo.export", (from, to))
+            .await
+            .map_err(|e| unsupported("repo.export", e))
+    }
+
+    /// Import a slice produced by `export_range`, the objects are added to the repo without
+    /// updating any branches. If the server rejects the slice the error is returned as an
+    /// `Error::Decode`, if it doesn't support `repo.import` an `ErrorKind::Unsupported` error is
+    /// returned.
+    pub async fn import(&self, data: &[u8]) -> std::io::Result<()> {
+        let invalid = |source| -> std::io::Error {
+            crate::Error::Decode {
+                type_name: "slice",
+                offset: 0,
+                source,
+            }
+            .into()
+        };
+
+        if data.is_empty() {
+            return Err(invalid(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Slice is empty",
+            )));
+        }
+
+        match self.write(self.client.call("repo.import", Raw(data))).await {
+            Err(e) if is_unknown_command(&e) => Err(unsupported("repo.import", e)),
+            Err(e) if is_server_error(&e) => Err(invalid(e)),
+            res => res,
+        }
+    }
+
+    /// Replace the root of the current branch with an empty tree in a single commit
+    pub async fn clear(&self, info: Info) -> std::io::Result<()> {
+        self.set_tree(&Key::empty(), &Tree::<Contents, H>::empty(), info)
+            .await
+    }
+}
+
+impl<H: Hash> Commit<H> {
+    /// Create a new commit
+    pub async fn create<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
+        client: &Client<Socket, Contents, H>,
+        node: &H,
+        parents: impl Into<Vec<H>>,
+        info: Info,
+    ) -> std::io::Result<Commit<H>> {
+        let parents = parents.into();
+        client.call("commit.v", (info, parents, node)).await
+    }
+
+    /// Load a commit by hash, returns `None` if the hash does not refer to a commit
+    pub async fn of_hash<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
+        client: &Client<Socket, Contents, H>,
+        hash: &H,
+    ) -> std::io::Result<Option<Commit<H>>> {
+        client.call("commit.of_hash", hash).await
+    }
+
+    /// Check if `self` is `other` or one of its ancestors by walking the parents of `other`.
+    /// Each commit is only loaded once, even if it is reachable through several merges.
+    pub async fn is_ancestor_of<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
+        &self,
+        client: &Client<Socket, Contents, H>,
+        other: &Commit<H>,
+    ) -> std::io::Result<bool> {
+        if self == other {
+            return Ok(true);
+        }
+
+        let mut visited = std::collections::HashSet::new();
+        let mut stack = other.parents.clone();
+        while let Some(hash) = stack.pop() {
+            if !visited.insert(hash_id(&hash)?) {
+                continue;
+            }
+
+            let commit = match Commit::of_hash(client, &hash).await? {
+                Some(commit) => commit,
+                None => continue,
+            };
+            if &commit == self {
+                return Ok(true);
+            }
+            stack.extend(commit.parents);
+        }
+        Ok(false)
+    }
+
+    /// Get the changes to contents between `parent` and this commit, sorted by key. Both trees
+    /// are downloaded using `Tree::to_concrete`, so this should be avoided for large trees.
+    pub async fn diff<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type + PartialEq>(
+        &self,
+        client: &Client<Socket, Contents, H>,
+        parent: &Commit<H>,
+    ) -> std::io::Result<Vec<(Key, Diff<Contents>)>> {
+        if self.node == parent.node {
+            return Ok(Vec::new());
+        }
+        let old = Tree::<Contents, H>::Hash(parent.node.clone())
+            .to_concrete(client)
+            .await?;
+        let new = Tree::<Contents, H>::Hash(self.node.clone())
+            .to_concrete(client)
+            .await?;
+        Ok(Concrete::diff(old, new))
+    }
+
+    /// Get the changes this commit made, by comparing it with its only parent. Commits without
+    /// parents are compared with an empty tree. Merge commits return an `InvalidInput` error
+    /// since there is more than one parent to compare with, use `diff` instead.
+    pub async fn changes<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type + PartialEq>(
+        &self,
+        client: &Client<Socket, Contents, H>,
+    ) -> std::io::Result<Vec<(Key, Diff<Contents>)>> {
+        match self.parents.as_slice() {
+            [] => {
+                let new = Tree::<Contents, H>::Hash(self.node.clone())
+                    .to_concrete(client)
+                    .await?;
+                Ok(Concrete::diff(Concrete::empty(), new))
+            }
+            [parent] => match Commit::of_hash(client, parent).await? {
+                Some(parent) => self.diff(client, &parent).await,
+                None => Err(Error::new(ErrorKind::NotFound, "Parent commit not found")),
+            },
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Merge commits have more than one parent, use diff to pick one",
+            )),
+        }
+    }
+}
+
+impl<T: Type, H: Hash> Tree<T, H> {
+    /// Add value to tree
+    pub async fn add<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
+        &self,
+        client: &Client<Socket, Contents, H>,
+        key: &Key,
+        value: &T,
+    ) -> std::io::Result<Tree<T, H>> {
+        client.call("tree.add", (self, key, value)).await
+    }
+
+    /// Add tree to tree
+    pub async fn add_tree<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
+        &self,
+        client: &Client<Socket, Contents, H>,
+        key: &Key,
+        tree: &Tree<T, H>,
+    ) -> std::io::Result<Tree<T, H>> {
+        client.call("tree.add_tree", (self, key, tree)).await
+    }
+
+    /// Remove key from tree
+    pub async fn remove<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
+        &self,
+        client: &Client<Socket, Contents, H>,
+        key: &Key,
+    ) -> std::io::Result<Tree<T, H>> {
+        client.call("tree.remove", (self, key)).await
+    }
+
+    /// Read the value at `key`, pass it to `f` and set `key` to the result, removing it when `f`
+    /// returns `None`. Returns the updated tree.
+    pub async fn update<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type, F>(
+        &self,
+        client: &Client<Socket, Contents, H>,
+        key: &Key,
+        f: F,
+    ) -> std::io::Result<Tree<T, H>>
+    where
+        F: FnOnce(Option<T>) -> Option<T>,
+    {
+        let value = self.find(client, key).await?;
+        match f(value) {
+            Some(value) => self.add(client, key, &value).await,
+            None => self.remove(client, key).await,
+        }
+    }
+
+    /// Find value in tree
+    pub async fn find<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
+        &self,
+        client: &Client<Socket, Contents, H>,
+        key: &Key,
+    ) -> std::io::Result<Option<T>> {
+        client.call("tree.find", (self, key)).await
+    }
+
+    /// Get value from tree, returns a `NotFound` error if the key is not set
+    pub async fn get<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
+        &self,
+        client: &Client<Socket, Contents, H>,
+        key: &Key,
+    ) -> std::io::Result<T> {
+        match self.find(client, key).await? {
+            Some(value) => Ok(value),
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Key not found in tree: {}", key.to_string()),
+            )),
+        }
+    }
+
+    /// Find tree in tree
+    pub async fn find_tree<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
+        &self,
+        client: &Client<Socket, Contents, H>,
+        key: &Key,
+    ) -> std::io::Result<Option<Tree<T, H>>> {
+        client.call("tree.find_tree", (self, key)).await
+    }
+
+    /// Check if tree key is a value
+    pub async fn mem<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
+        &self,
+        client: &Client<Socket, Contents, H>,
+        key: &Key,
+    ) -> std::io::Result<bool> {
+        client.call("tree.mem", (self, key)).await
+    }
+
+    /// Check if tree key is a tree
+    pub async fn mem_tree<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
+        &self,
+        client: &Client<Socket, Contents, H>,
+        key: &Key,
+    ) -> std::io::Result<bool> {
+        client.call("tree.mem_tree", (self, key)).await
+    }
+
+    /// List the steps directly under a key, along with the kind of node each one points to
+    pub async fn list<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
+        &self,
+        client: &Client<Socket, Contents, H>,
+        key: &Key,
+    ) -> std::io::Result<Vec<(String, Kind)>> {
+        client.call("tree.list", (self, key)).await
+    }
+
+    /// Download the whole tree into a `Concrete` tree that can be used without any further
+    /// requests. Every node is held in memory, so this should be avoided for large trees.
+    /// Metadata isn't included. Requests are batched, taking two round trips for each level of
+    /// the tree.
+    pub async fn to_concrete<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
+        &self,
+        client: &Client<Socket, Contents, H>,
+    ) -> std::io::Result<Concrete<T>> {
+        // `T` isn't required to implement `Clone`, so local trees are copied by re-encoding them
+        if let Tree::Concrete(c) = self {
+            let mut data = Vec::new();
+            c.encode_bin(&mut data)?;
+            return crate::r#type::decode_slice(&data);
+        }
+
+        let mut root = Concrete::empty();
+        let mut dirs = vec![Key::empty()];
+        while !dirs.is_empty() {
+            let lists: Vec<Vec<(String, Kind)>> = client
+                .conn
+                .call_many("tree.list", dirs.iter().map(|key| (self, key)))
+                .await?;
+
+            let mut contents = Vec::new();
+            let mut next = Vec::new();
+            for (dir, items) in dirs.iter().zip(lists) {
+                for (step, kind) in items {
+                    let mut key = dir.clone();
+                    key.push(step);
+                    match kind {
+                        Kind::Contents => contents.push(key),
+                        Kind::Tree => {
+                            root.insert(&key, Concrete::empty());
+                            next.push(key);
+                        }
+                    }
+                }
+            }
+
+            let values: Vec<Option<T>> = client
+                .conn
+                .call_many("tree.find", contents.iter().map(|key| (self, key)))
+                .await?;
+            for (key, value) in contents.iter().zip(values) {
+                if let Some(value) = value {
+                    root.insert(key, Concrete::Contents(value));
+                }
+            }
+            dirs = next;
+        }
+        Ok(root)
+    }
+
+    /// Upload a `Concrete` tree in a single request, returning a tree that refers to the copy
+    /// stored by the server. Returns an error if a step is empty or contains a `/`, since it
+    /// wouldn't refer to a single node on the server.
+    pub async fn of_concrete<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
+        client: &Client<Socket, Contents, H>,
+        c: Concrete<T>,
+    ) -> std::io::Result<Tree<T, H>> {
+        let mut stack = vec![&c];
+        while let Some(node) = stack.pop() {
+            if let Concrete::Tree(t) = node {
+                for (step, child) in t {
+                    if step.is_empty() || step.contains('/') {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("Invalid step in tree: {:?}", step),
+                        ));
+                    }
+                    stack.push(child);
+                }
+            }
+        }
+
+        let tree = Tree::<T, H>::Concrete(c);
+        client
+            .conn
+            .call_tree(
+                "tree.add_tree",
+                (Tree::<T, H>::empty(), Key::empty()),
+                &tree,
+            )
+            .await
+    }
+
+    /// Build a new tree on the server by applying `f` to every value in this tree. The whole
+    /// tree is traversed and downloaded using `to_concrete`, then every node is rewritten and
+    /// uploaded using `of_concrete`, so this should be avoided for large trees. Metadata isn't
+    /// kept.
+    pub async fn map_contents<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type, U: Type, F>(
+        &self,
+        client: &Client<Socket, Contents, H>,
+        f: F,
+    ) -> std::io::Result<Tree<U, H>>
+    where
+        F: Fn(&T) -> U,
+    {
+        let c = self.to_concrete(client).await?;
+        Tree::of_concrete(client, c.map(&f)).await
+    }
+
+    /// Recursively collect the key of every contents node in the tree
+    pub async fn paths<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type>(
+        &self,
+        client: &Client<Socket, Contents, H>,
+    ) -> std::io::Result<Vec<Key>> {
+        let mut paths = Vec::new();
+        let mut stack = vec![Key::empty()];
+        while let Some(key) = stack.pop() {
+            for (step, kind) in self.list(client, &key).await? {
+                let mut child = key.clone();
+                child.push(step);
+                match kind {
+                    Kind::Contents => paths.push(child),
+                    Kind::Tree => stack.push(child),
+                }
+            }
+        }
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testutil::MockServer;
+    use crate::prelude::*;
+    use std::time::Duration;
+    use tokio::io::*;
+
+    fn skip() -> std::io::Result<()> {
+        eprintln!("Skipping client test: client not connected, perhaps the server isn't running?");
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn test_pool() -> std::io::Result<()> {
+        // Minimal server that only completes the handshake
+        let server = MockServer::new(|_, _| unreachable!()).await?;
+        let addr = server.addr();
+
+        let pool = Pool::<Tcp, Bytes, Blake2b>::tcp(addr, 2);
+        for _ in 0..4 {
+            let mut clients = Vec::new();
+            for _ in 0..8 {
+                clients.push(pool.get().await?);
+            }
+            drop(clients);
+            assert_eq!(pool.idle(), 2);
+        }
+
+        let client = pool.get().await?;
+        assert!(client.endpoint() == Some(addr));
+        assert_eq!(pool.idle(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs() -> std::io::Result<()> {
+        // Minimal server that only completes the handshake
+        let server = MockServer::new(|_, _| unreachable!()).await?;
+        let addr = server.addr();
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await?
+            .local_addr()?;
+
+        // An IPv6 address that never responds, followed by a refused and a working IPv4 address.
+        // The working address is tried as soon as the refused one fails, without waiting for
+        // another delay.
+        let addrs: Vec<std::net::SocketAddr> = vec!["[100::1]:9".parse().unwrap(), closed, addr];
+        let start = std::time::Instant::now();
+        let client = tokio::time::timeout(
+            Duration::from_secs(5),
+            Client::<Tcp, Bytes, Blake2b>::new(addrs.as_slice()),
+        )
+        .await??;
+        assert!(client.endpoint() == Some(addr));
+        assert!(start.elapsed() < Duration::from_millis(450));
+
+        let e = Client::<Tcp, Bytes, Blake2b>::new(closed)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pre_handshake() -> std::io::Result<()> {
+        // Server that expects a token line before the handshake
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (conn, _) = listener.accept().await?;
+            let mut conn = BufStream::new(conn);
+            let mut line = String::new();
+            conn.read_line(&mut line).await?;
+            if line != "token\n" {
+                return Ok(());
+            }
+            line.clear();
+            conn.read_line(&mut line).await?;
+            conn.write_all(line.as_bytes()).await?;
+            conn.flush().await?;
+            let mut rest = Vec::new();
+            conn.read_to_end(&mut rest).await?;
+            std::io::Result::Ok(())
+        });
+
+        let client = Client::<Tcp, Bytes, Blake2b>::builder()
+            .pre_handshake(|mut socket| async move {
+                socket.write_all(b"token\n").await?;
+                Ok(socket)
+            })
+            .connect(addr)
+            .await?;
+        assert!(client.endpoint() == Some(addr));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_builder_options() -> std::io::Result<()> {
+        // Server that only accepts switching to the `dev` branch
+        let server = MockServer::new(|command, data| {
+            assert_eq!(command, "set_current_branch");
+            let branch: String = crate::r#type::decode_slice(data).unwrap();
+            assert_eq!(branch, "dev");
+            MockServer::ok(())
+        })
+        .await?;
+        let addr = server.addr();
+
+        let client = Client::<Tcp, Bytes, Blake2b>::builder()
+            .timeout(Duration::from_secs(5))
+            .write_timeout(Duration::from_secs(1))
+            .buffers(64, 64)
+            .branch("dev")
+            .connect(addr)
+            .await?;
+        assert_eq!(client.read_timeout(), Some(Duration::from_secs(5)));
+        assert_eq!(client.write_timeout(), Some(Duration::from_secs(1)));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_abstract_socket() -> std::io::Result<()> {
+        use std::os::linux::net::SocketAddrExt;
+
+        // Server in the abstract namespace that only completes the handshake
+        let name = format!("irmin-rs-test-{}", std::process::id());
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name)?;
+        let listener = std::os::unix::net::UnixListener::bind_addr(&addr)?;
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::UnixListener::from_std(listener)?;
+        tokio::spawn(async move {
+            let (conn, _) = listener.accept().await?;
+            let mut conn = BufStream::new(conn);
+            let mut line = String::new();
+            conn.read_line(&mut line).await?;
+            conn.write_all(line.as_bytes()).await?;
+            conn.flush().await?;
+            let mut rest = Vec::new();
+            conn.read_to_end(&mut rest).await?;
+            std::io::Result::Ok(())
+        });
+
+        let client = Client::<Unix, Bytes, Blake2b>::new_abstract(&name).await?;
+        client.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_handshake() -> std::io::Result<()> {
+        // Server that responds to the handshake with the wrong hash
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (conn, _) = listener.accept().await?;
+            let mut conn = BufStream::new(conn);
+            let mut line = String::new();
+            conn.read_line(&mut line).await?;
+            conn.write_all(b"abc\n").await?;
+            conn.flush().await?;
+            std::io::Result::Ok(())
+        });
+
+        let e = Client::<Tcp, Bytes, Blake2b>::new(addr)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+        let msg = e.to_string();
+        assert!(
+            msg.contains("\"abc\"")
+                && msg.contains(&Client::<Tcp, Bytes, Blake2b>::handshake_hash())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() -> std::io::Result<()> {
+        // Server that completes the handshake and then never responds
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (conn, _) = listener.accept().await?;
+            let mut conn = BufStream::new(conn);
+            let mut line = String::new();
+            conn.read_line(&mut line).await?;
+            conn.write_all(line.as_bytes()).await?;
+            conn.flush().await?;
+            let mut rest = Vec::new();
+            conn.read_to_end(&mut rest).await?;
+            std::io::Result::Ok(())
+        });
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        assert!(client.set_read_timeout(Some(Duration::ZERO)).is_err());
+        client.set_read_timeout(Some(Duration::from_millis(50)))?;
+        assert_eq!(client.read_timeout(), Some(Duration::from_millis(50)));
+        assert_eq!(client.write_timeout(), None);
+
+        let e = client.ping().await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert!(client.is_poisoned());
+        let e = client.ping().await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotConnected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_length() -> std::io::Result<()> {
+        // Server that responds to `ping` with a negative length and to anything else with a
+        // length too large to allocate
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut conn = BufStream::new(conn);
+                    let mut line = String::new();
+                    conn.read_line(&mut line).await?;
+                    conn.write_all(line.as_bytes()).await?;
+                    conn.flush().await?;
+                    loop {
+                        line.clear();
+                        if conn.read_line(&mut line).await? == 0 {
+                            break;
+                        }
+                        let mut data = vec![0u8; conn.read_i64().await? as usize];
+                        conn.read_exact(&mut data).await?;
+                        conn.write_u8(0).await?;
+                        conn.write_i64(if line == "ping\n" { -1 } else { i64::MAX })
+                            .await?;
+                        conn.flush().await?;
+                    }
+                    std::io::Result::Ok(())
+                });
+            }
+        });
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        let e = client.ping().await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(client.is_poisoned());
+        client.discard();
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        let e = client.get_current_branch().await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(client.is_poisoned());
+        client.discard();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_key_limits() -> std::io::Result<()> {
+        // Server that responds to every request with the key `a/b`
+        let server = MockServer::new(|_, _| MockServer::ok(Key::new(["a", "b"]))).await?;
+        let addr = server.addr();
+
+        let limits = crate::KeyLimits {
+            max_steps: 1,
+            ..crate::KeyLimits::default()
+        };
+        let limited = Client::<Tcp, Bytes, Blake2b>::builder()
+            .key_limits(limits)
+            .connect(addr)
+            .await?;
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        assert_eq!(limited.key_limits(), limits);
+        let e = limited.call::<Key>("key", ()).await.unwrap_err();
+        assert!(matches!(
+            crate::Error::of_io(&e),
+            Some(crate::Error::Decode { .. })
+        ));
+
+        // Other clients, and decoding outside of a client, keep the default limits
+        assert_eq!(client.call::<Key>("key", ()).await?, Key::new(["a", "b"]));
+        assert_eq!(Key::decode_limits(), crate::KeyLimits::default());
+        limited.close().await?;
+        client.close().await
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_request() -> std::io::Result<()> {
+        // Server that answers the first request and then never responds
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (conn, _) = listener.accept().await?;
+            let mut conn = BufStream::new(conn);
+            let mut line = String::new();
+            conn.read_line(&mut line).await?;
+            conn.write_all(line.as_bytes()).await?;
+            conn.flush().await?;
+            line.clear();
+            conn.read_line(&mut line).await?;
+            let mut data = vec![0u8; conn.read_i64().await? as usize];
+            conn.read_exact(&mut data).await?;
+            conn.write_u8(0).await?;
+            conn.write_i64(0).await?;
+            conn.flush().await?;
+            let mut rest = Vec::new();
+            conn.read_to_end(&mut rest).await?;
+            std::io::Result::Ok(())
+        });
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        client.ping().await?;
+        assert!(client.is_connected());
+        assert!(!client.is_poisoned());
+
+        let res = tokio::time::timeout(Duration::from_millis(50), client.ping()).await;
+        assert!(res.is_err());
+        assert!(client.is_poisoned());
+        assert!(!client.is_connected());
+        let e = client.ping().await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotConnected);
+        client.discard();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_stream() -> std::io::Result<()> {
+        // Server that lists the children `a`, `b` and `c` two at a time
+        let server = MockServer::new(|command, data| {
+            assert_eq!(command, "store.list_page");
+            let (_, token, limit): (Key, Option<String>, usize) =
+                crate::r#type::decode_slice(data).unwrap();
+            assert_eq!(limit, 2);
+            match token.as_deref() {
+                None => MockServer::ok((
+                    vec![
+                        ("a".to_string(), Kind::Contents),
+                        ("b".to_string(), Kind::Tree),
+                    ],
+                    Some("b".to_string()),
+                )),
+                Some("b") => {
+                    MockServer::ok((vec![("c".to_string(), Kind::Contents)], None::<String>))
+                }
+                Some(_) => unreachable!(),
+            }
+        })
+        .await?;
+        let addr = server.addr();
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        let store = client.store();
+        let key = Key::new(["x"]);
+        let mut stream = store.list_stream(&key).with_page_size(2);
+        assert_eq!(
+            stream.next().await.transpose()?,
+            Some((Key::new(["x", "a"]), Kind::Contents))
+        );
+        let rest = stream.collect().await?;
+        assert_eq!(
+            rest,
+            vec![
+                (Key::new(["x", "b"]), Kind::Tree),
+                (Key::new(["x", "c"]), Kind::Contents)
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_recursive() -> std::io::Result<()> {
+        // Server with the contents `a`, `b/c` and `b/d/e` under the key `x`
+        let server = MockServer::new(|command, data| match command {
+            "store.find_tree" => MockServer::ok(Some(Tree::<Bytes, Blake2b>::Id(1))),
+            "tree.list" => {
+                let (_, key): (Tree<Bytes, Blake2b>, Key) =
+                    crate::r#type::decode_slice(data).unwrap();
+                let items: Vec<(String, Kind)> = match key.to_string().as_str() {
+                    "" => vec![("a".into(), Kind::Contents), ("b".into(), Kind::Tree)],
+                    "b" => vec![("c".into(), Kind::Contents), ("d".into(), Kind::Tree)],
+                    "b/d" => vec![("e".into(), Kind::Contents)],
+                    _ => vec![],
+                };
+                MockServer::ok(items)
+            }
+            _ => unreachable!(),
+        })
+        .await?;
+        let addr = server.addr();
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        let store = client.store();
+        let key = Key::new(["x"]);
+        let mut all = store.list_recursive(&key, None).await?;
+        all.sort();
+        assert_eq!(
+            all,
+            vec![
+                Key::new(["x", "a"]),
+                Key::new(["x", "b", "c"]),
+                Key::new(["x", "b", "d", "e"])
+            ]
+        );
+        let mut shallow = store.list_recursive(&key, Some(2)).await?;
+        shallow.sort();
+        assert_eq!(
+            shallow,
+            vec![Key::new(["x", "a"]), Key::new(["x", "b", "c"])]
+        );
+        assert!(store.list_recursive(&key, Some(0)).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update_with() -> std::io::Result<()> {
+        // Server where the value is changed by another writer before the first compare-and-set
+        let mut value = 1i64;
+        let mut conflicts = 1;
+        let server = MockServer::new(move |command, data| match command {
+            "store.find" => MockServer::ok(Some(value)),
+            "store.test_and_set" => {
+                let (_, _, (test, set)): (Key, Info, (Option<i64>, Option<i64>)) =
+                    crate::r#type::decode_slice(data).unwrap();
+                let ok = conflicts == 0 && test == Some(value);
+                if conflicts > 0 {
+                    conflicts -= 1;
+                    value += 10;
+                } else if ok {
+                    value = set.unwrap();
+                }
+                MockServer::ok(ok)
+            }
+            _ => unreachable!(),
+        })
+        .await?;
+        let addr = server.addr();
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        let store = client.store();
+        let key = Key::new(["counter"]);
+        let incr = |x: Option<&i64>| x.map(|x| x + 1);
+        let e = store
+            .update_with_retries(&key, 0, incr, Info::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            crate::Error::of_io(&e),
+            Some(crate::Error::Conflict { attempts: 1 })
+        ));
+        assert_eq!(store.update_with(&key, incr, Info::new()).await?, Some(12));
+        Ok(())
+    }
+
+    /// Server that only responds to `commit.of_hash` using `commits`, along with the number of
+    /// requests it has answered
+    async fn commit_server(
+        commits: Vec<(Blake2b, Commit<Blake2b>)>,
+    ) -> std::io::Result<(MockServer, std::sync::Arc<std::sync::atomic::AtomicUsize>)> {
+        let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let count = requests.clone();
+        let server = MockServer::new(move |command, data| {
+            assert_eq!(command, "commit.of_hash");
+            let h: Blake2b = crate::r#type::decode_slice(data).unwrap();
+            count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            MockServer::ok(commits.iter().find(|(x, _)| x == &h).map(|(_, c)| c))
+        })
+        .await?;
+        Ok((server, requests))
+    }
+
+    /// History where `d` merges `b` and `c`, which both have `a` as their parent
+    fn merge_history() -> Vec<(Blake2b, Commit<Blake2b>)> {
+        let hash = |n: u8| Blake2b(vec![n; Blake2b::size()]);
+        let commit = |parents: Vec<Blake2b>, message: &str| {
+            Commit::new(hash(0), parents, Info::new().with_message(message))
+        };
+        vec![
+            (hash(1), commit(vec![], "a")),
+            (hash(2), commit(vec![hash(1)], "b")),
+            (hash(3), commit(vec![hash(1)], "c")),
+            (hash(4), commit(vec![hash(2), hash(3)], "d")),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_is_ancestor_of() -> std::io::Result<()> {
+        let commits = merge_history();
+        let (a, c, d) = (
+            commits[0].1.clone(),
+            commits[2].1.clone(),
+            commits[3].1.clone(),
+        );
+        let e = Commit::new(a.node.clone(), vec![], Info::new().with_message("e"));
+        let (server, requests) = commit_server(commits).await?;
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(server.addr()).await?;
+        assert!(d.is_ancestor_of(&client, &d).await?);
+        assert!(c.is_ancestor_of(&client, &d).await?);
+        assert!(!d.is_ancestor_of(&client, &a).await?);
+        assert!(!e.is_ancestor_of(&client, &d).await?);
+        client.close().await?;
+
+        // Finding `c` loads it directly, the last walk only loads `a` once even though both `b`
+        // and `c` point to it
+        assert_eq!(requests.load(std::sync::atomic::Ordering::Relaxed), 1 + 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_commits_between() -> std::io::Result<()> {
+        let commits = merge_history();
+        let (a, b, c, d) = (
+            commits[0].1.clone(),
+            commits[1].1.clone(),
+            commits[2].1.clone(),
+            commits[3].1.clone(),
+        );
+        let (server, _) = commit_server(commits).await?;
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(server.addr()).await?;
+        let store = client.store();
+        assert_eq!(
+            store.commits_between(&a, &d).await?,
+            vec![d.clone(), c.clone(), b.clone()]
+        );
+        assert_eq!(store.commits_between(&b, &d).await?, vec![d.clone(), c]);
+        assert!(store.commits_between(&d, &d).await?.is_empty());
+        assert!(store.commits_between(&d, &b).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_commit_diff() -> std::io::Result<()> {
+        fn tree(entries: &[(&[&str], &str)]) -> std::io::Result<Concrete<String>> {
+            let mut builder = TreeBuilder::new();
+            for (key, value) in entries {
+                builder = builder.add(Key::new(key), value.to_string());
+            }
+            match builder.build::<Blake2b>()? {
+                Tree::Concrete(c) => Ok(c),
+                _ => unreachable!(),
+            }
+        }
+
+        // Server that stores two trees, looked up by hash, and the parent commit
+        let old = tree(&[(&["a"], "1"), (&["b", "c"], "2"), (&["d"], "3")])?;
+        let new = tree(&[
+            (&["a"], "1"),
+            (&["b", "c"], "5"),
+            (&["d", "e"], "3"),
+            (&["f"], "6"),
+        ])?;
+        let old_hash = Blake2b::hash("old");
+        let new_hash = Blake2b::hash("new");
+        let parent = Commit::new(old_hash.clone(), vec![], Info::new());
+        let server_parent = parent.clone();
+        let server_old_hash = old_hash.clone();
+        let server = MockServer::new(move |command, data| {
+            if command == "commit.of_hash" {
+                return MockServer::ok(Some(&server_parent));
+            }
+            let (t, key): (Tree<String, Blake2b>, Key) = crate::r#type::decode_slice(data).unwrap();
+            let mut node = match t {
+                Tree::Hash(h) if h == server_old_hash => Some(&old),
+                Tree::Hash(_) => Some(&new),
+                _ => unreachable!(),
+            };
+            for step in key.steps() {
+                node = match node {
+                    Some(Concrete::Tree(t)) => t.get(step),
+                    _ => None,
+                };
+            }
+            match (command, node) {
+                ("tree.list", Some(Concrete::Tree(t))) => MockServer::ok(
+                    t.iter()
+                        .map(|(step, child)| {
+                            let kind = match child {
+                                Concrete::Tree(_) => Kind::Tree,
+                                Concrete::Contents(_) => Kind::Contents,
+                            };
+                            (step.clone(), kind)
+                        })
+                        .collect::<Vec<_>>(),
+                ),
+                ("tree.find", Some(Concrete::Contents(v))) => MockServer::ok(Some(v)),
+                _ => unreachable!(),
+            }
+        })
+        .await?;
+        let addr = server.addr();
+
+        let client = Client::<Tcp, String, Blake2b>::new(addr).await?;
+        let commit = Commit::new(new_hash, vec![old_hash.clone()], Info::new());
+        let expected = vec![
+            (
+                Key::new(["b", "c"]),
+                Diff::Updated("2".to_string(), "5".to_string()),
+            ),
+            (Key::new(["d"]), Diff::Removed("3".to_string())),
+            (Key::new(["d", "e"]), Diff::Added("3".to_string())),
+            (Key::new(["f"]), Diff::Added("6".to_string())),
+        ];
+        assert_eq!(commit.diff(&client, &parent).await?, expected);
+        assert_eq!(commit.changes(&client).await?, expected);
+        assert!(parent.diff(&client, &parent).await?.is_empty());
+
+        let merge = Commit::new(old_hash.clone(), vec![old_hash.clone(); 2], Info::new());
+        let e = merge.changes(&client).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        client.close().await
+    }
+
+    #[tokio::test]
+    async fn test_branch_heads() -> std::io::Result<()> {
+        // Server with the branches `main` and `dev`, where `empty` has no commits
+        let head = Commit::new(Blake2b::hash("node"), vec![], Info::new());
+        let server_head = head.clone();
+        let server = MockServer::new(move |command, data| match command {
+            "branch.list" => MockServer::ok(vec!["dev", "empty", "main"]),
+            "branch.find" => {
+                let branch: String = crate::r#type::decode_slice(data).unwrap();
+                MockServer::ok(Some(&server_head).filter(|_| branch != "empty"))
+            }
+            _ => unreachable!(),
+        })
+        .await?;
+        let addr = server.addr();
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        let store = client.store();
+        assert_eq!(
+            store.branch_heads().await?,
+            vec![
+                ("dev".to_string(), head.clone()),
+                ("main".to_string(), head)
+            ]
+        );
+        client.close().await
+    }
+
+    #[tokio::test]
+    async fn test_map_contents() -> std::io::Result<()> {
+        // Server that responds to `tree.add_tree` with the tree that was added
+        let server = MockServer::new(|command, data| {
+            assert_eq!(command, "tree.add_tree");
+            let (_, _, tree): (Tree<i64, Blake2b>, Key, Tree<i64, Blake2b>) =
+                crate::r#type::decode_slice(data).unwrap();
+            MockServer::ok(tree)
+        })
+        .await?;
+        let addr = server.addr();
+
+        let client = Client::<Tcp, String, Blake2b>::new(addr).await?;
+        let tree = TreeBuilder::new()
+            .add(Key::new(["a"]), "1".to_string())
+            .add(Key::new(["b", "c"]), "22".to_string())
+            .build::<Blake2b>()?;
+        let mapped = tree
+            .map_contents(&client, |s: &String| s.len() as i64)
+            .await?;
+        let expected = TreeBuilder::new()
+            .add(Key::new(["a"]), 1i64)
+            .add(Key::new(["b", "c"]), 2i64)
+            .build::<Blake2b>()?;
+        assert_eq!(mapped, expected);
+        client.close().await
+    }
+
+    #[tokio::test]
+    async fn test_set_returning() -> std::io::Result<()> {
+        // Server that only acknowledges the second `store.set` with the new head
+        let head = Commit::new(
+            Blake2b::hash("node"),
+            vec![],
+            Info::new().with_message("head"),
+        );
+        let server_head = head.clone();
+        let mut acked = false;
+        let mut expect_head = false;
+        let server = MockServer::new(move |command, _| {
+            // Writes that aren't acknowledged must be followed by a request for the head
+            assert!(!expect_head || command == "store.head");
+            expect_head = false;
+            match command {
+                "store.set" if acked => MockServer::ok(&server_head),
+                "store.set" | "store.remove" | "store.set_tree" => {
+                    acked = true;
+                    expect_head = true;
+                    MockServer::ok(())
+                }
+                "store.head" => MockServer::ok(Some(&server_head)),
+                _ => unreachable!(),
+            }
+        })
+        .await?;
+        let addr = server.addr();
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        let store = client.store();
+        let key = Key::new(["a"]);
+        for _ in 0..2 {
+            assert_eq!(store.set_returning(&key, 1i64, Info::new()).await?, head);
+        }
+        assert_eq!(store.remove_returning(&key, Info::new()).await?, head);
+        let tree = Tree::<Bytes, Blake2b>::empty();
+        assert_eq!(
+            store.set_tree_returning(&key, &tree, Info::new()).await?,
+            head
+        );
+        assert_eq!(store.generation(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_many() -> std::io::Result<()> {
+        // Server that records the keys that are set
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let server = MockServer::new(move |command, data| {
+            assert_eq!(command, "store.set");
+            let (key, _, value): (Key, Info, i64) = crate::r#type::decode_slice(data).unwrap();
+            let _ = tx.send(format!("{} {}", key.to_string(), value));
+            MockServer::ok(())
+        })
+        .await?;
+
+        let client = server.client::<Bytes, Blake2b>().await?;
+        client.set_flush_every_n(2);
+        let store = client.store();
+        let items: Vec<(Key, i64)> = (0..3)
+            .map(|i| (Key::new(["k", i.to_string().as_str()]), i))
+            .collect();
+        store.set_many(&items, Info::new()).await?;
+        let mut sets = Vec::new();
+        for _ in 0..3 {
+            sets.push(rx.recv().await.unwrap());
+        }
+        assert_eq!(sets, ["k/0 0", "k/1 1", "k/2 2"]);
+        assert_eq!(store.generation(), 1);
+        client.close().await
+    }
+
+    #[tokio::test]
+    async fn test_nested_requests() -> std::io::Result<()> {
+        // Server that responds to `store.find` with the requested key as a string
+        let server = MockServer::new(|_, data| {
+            let key: Key = crate::r#type::decode_slice(data).unwrap();
+            MockServer::ok(Some(key.to_string()))
+        })
+        .await?;
+        let addr = server.addr();
+
+        // Each request is issued while others are in flight, and from inside the handling of
+        // another response, every one must still get its own response
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        let store = client.store();
+        let find = |n: usize| {
+            let store = &store;
+            async move {
+                let key = Key::from_string(n.to_string());
+                let found: Option<String> = store.find(&key).await?;
+                assert_eq!(found, Some(key.to_string()));
+                let nested = Key::from_string(format!("{}/nested", n));
+                let found: Option<String> = store.find(&nested).await?;
+                assert_eq!(found, Some(nested.to_string()));
+                std::io::Result::Ok(())
+            }
+        };
+        tokio::try_join!(find(0), find(1), find(2), find(3))?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_empty() -> std::io::Result<()> {
+        // Server where `empty` is set to an empty value and every other key is missing
+        let server = MockServer::new(|_, data| {
+            let key: Key = crate::r#type::decode_slice(data).unwrap();
+            if key == Key::new(["empty"]) {
+                MockServer::ok(Some(Bytes::empty()))
+            } else {
+                MockServer::ok(None::<Bytes>)
+            }
+        })
+        .await?;
+        let addr = server.addr();
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        let store = client.store();
+        let empty = Key::new(["empty"]);
+        let missing = Key::new(["missing"]);
+        assert_eq!(store.find::<Bytes>(&empty).await?, Some(Bytes::empty()));
+        assert_eq!(store.find::<Bytes>(&missing).await?, None);
+
+        let mut buf = Vec::new();
+        assert!(store.find_to_writer(&empty, &mut buf).await?);
+        assert!(!store.find_to_writer(&missing, &mut buf).await?);
+        assert!(buf.is_empty());
+
+        client.set_flush_every_n(2);
+        let keys = [empty.clone(), missing.clone(), empty.clone()];
+        let found: Vec<Option<Bytes>> = store.find_many(&keys).await?;
+        assert_eq!(
+            found,
+            vec![Some(Bytes::empty()), None, Some(Bytes::empty())]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_with_hash() -> std::io::Result<()> {
+        // Server without `store.find_with_hash`, where every key is set to `value`
+        let server = MockServer::new(|command, data| match command {
+            "store.find_with_hash" => {
+                let key: Key = crate::r#type::decode_slice(data).unwrap();
+                if key == Key::new(["fails"]) {
+                    MockServer::error("failed")
+                } else {
+                    MockServer::error(&format!("Unknown command: {}", command))
+                }
+            }
+            "store.find" => MockServer::ok(Some(Bytes::from("value".as_bytes()))),
+            "store.stat" => MockServer::ok(Some(NodeStat {
+                kind: Kind::Contents,
+                hash: Blake2b::hash("value"),
+            })),
+            _ => unreachable!(),
+        })
+        .await?;
+        let addr = server.addr();
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        let store = client.store();
+        let (value, hash) = store
+            .find_with_hash::<Bytes>(&Key::new(["a"]))
+            .await?
+            .unwrap();
+        assert_eq!(value.as_ref(), b"value");
+        assert_eq!(hash, Blake2b::hash("value"));
+        assert!(store.exists(&Key::new(["a"])).await?);
+
+        // Other errors aren't mistaken for an unsupported command
+        let e = store
+            .find_with_hash::<Bytes>(&Key::new(["fails"]))
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "failed");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stock_server() -> std::io::Result<()> {
+        // Server that only supports the commands every irmin-server has
+        let server = MockServer::new(|command, _| match command {
+            "store.find" => MockServer::ok(Some(Bytes::from("value".as_bytes()))),
+            "store.mem" => MockServer::ok(true),
+            "store.mem_tree" => MockServer::ok(false),
+            "store.set" => MockServer::ok(()),
+            "commit.of_hash" => MockServer::ok(None::<Commit<Blake2b>>),
+            _ => MockServer::error(&format!("unknown command: {}", command)),
+        })
+        .await?;
+
+        fn is_unsupported<T>(res: std::io::Result<T>) -> bool {
+            matches!(res, Err(e) if e.kind() == ErrorKind::Unsupported)
+        }
+
+        let client = server.client::<Bytes, Blake2b>().await?;
+        let store = client.store();
+        let key = Key::new(["a"]);
+        assert!(is_unsupported(store.stat(&key).await));
+        assert!(store.exists(&key).await?);
+        assert!(is_unsupported(store.tree_hash(&key).await));
+        let value = |s: &'static str| Bytes::from(s.as_bytes());
+        assert!(
+            !store
+                .set_if_changed(&key, value("value"), Info::new())
+                .await?
+        );
+        assert!(
+            store
+                .set_if_changed(&key, value("other"), Info::new())
+                .await?
+        );
+
+        let head = Commit::new(Blake2b::hash("node"), vec![], Info::new());
+        assert!(is_unsupported(store.export_range(&head, &head).await));
+        assert!(is_unsupported(store.import(b"slice").await));
+        assert!(is_unsupported(store.branch_head("main").await));
+        assert!(is_unsupported(store.branch_heads().await));
+        assert!(!store.mem_commit(&Blake2b::hash("commit")).await?);
+        let tree = Tree::<Bytes, Blake2b>::empty();
+        assert!(is_unsupported(
+            store.merge_tree(&key, &tree, Info::new()).await
+        ));
+        assert!(is_unsupported(store.find_all::<Bytes, ()>(&key).await));
+        let res = store.set_all(&key, value("value"), (), Info::new()).await;
+        assert!(is_unsupported(res));
+        let res = store.set_tree_checked(&key, &tree, None, Info::new()).await;
+        assert!(is_unsupported(res));
+        let res = store
+            .test_and_set_tree(&key, None, Some(&tree), Info::new())
+            .await;
+        assert!(is_unsupported(res));
+        client.close().await
+    }
+
+    #[tokio::test]
+    async fn test_store_tree() -> std::io::Result<()> {
+        // Server where the branch is empty for the first `store.tree` request, after that the
+        // command is unknown and the root tree can only be found using `store.find_tree`
+        let mut empty = true;
+        let server = MockServer::new(move |command, data| match command {
+            "store.tree" if empty => {
+                empty = false;
+                MockServer::ok(None::<Tree<Bytes, Blake2b>>)
+            }
+            "store.find_tree" => {
+                let key: Key = crate::r#type::decode_slice(data).unwrap();
+                assert!(key.is_empty());
+                MockServer::ok(Some(Tree::<Bytes, Blake2b>::Id(1)))
+            }
+            _ => MockServer::error(&format!("unknown command: {}", command)),
+        })
+        .await?;
+        let addr = server.addr();
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        let store = client.store();
+        assert_eq!(store.tree::<Bytes>().await?, Tree::empty());
+        assert_eq!(store.tree::<Bytes>().await?, Tree::Id(1));
+        client.close().await
+    }
+
+    #[tokio::test]
+    async fn test_merge_tree() -> std::io::Result<()> {
+        // Server where merging into `a` conflicts and merging anywhere else succeeds
+        let server = MockServer::new(|command, data| {
+            assert_eq!(command, "store.merge_tree");
+            let (key, _, tree): (Key, Info, Tree<Bytes, Blake2b>) =
+                crate::r#type::decode_slice(data).unwrap();
+            assert_eq!(tree, Tree::empty());
+            let res: std::result::Result<(), String> = if key == Key::new(["a"]) {
+                Err("conflict".into())
+            } else {
+                Ok(())
+            };
+            MockServer::ok(res)
+        })
+        .await?;
+        let addr = server.addr();
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        let store = client.store();
+        let tree = Tree::<Bytes, Blake2b>::empty();
+        let res = store
+            .merge_tree(&Key::new(["a"]), &tree, Info::new())
+            .await?;
+        assert_eq!(res, Err("conflict".to_string()));
+        let res = store
+            .merge_tree(&Key::new(["b"]), &tree, Info::new())
+            .await?;
+        assert_eq!(res, Ok(()));
+        assert_eq!(store.generation(), 2);
+        assert_eq!(client.store().generation(), 2);
+        client.close().await
+    }
+
+    #[tokio::test]
+    async fn test_generation() -> std::io::Result<()> {
+        // Server that accepts every write
+        let server = MockServer::new(|command, _| match command {
+            "store.merge" | "store.merge_into" | "store.merge_tree" => {
+                MockServer::ok(Ok::<(), String>(()))
+            }
+            "store.test_and_set" | "store.set_tree_checked" => MockServer::ok(true),
+            "store.find" => MockServer::ok(None::<i64>),
+            _ => MockServer::ok(()),
+        })
+        .await?;
+
+        let client = server.client::<Bytes, Blake2b>().await?;
+        let store = client.store();
+        let key = Key::new(["a"]);
+        let tree = Tree::<Bytes, Blake2b>::empty();
+        store.set(&key, 1i64, Info::new()).await?;
+        store.set_all(&key, 1i64, (), Info::new()).await?;
+        store.set_many(&[(key.clone(), 1i64)], Info::new()).await?;
+        store
+            .test_and_set(&key, None, Some(&1i64), Info::new())
+            .await?;
+        store.set_tree(&key, &tree, Info::new()).await?;
+        store
+            .set_tree_checked(&key, &tree, None, Info::new())
+            .await?;
+        store.remove(&key, Info::new()).await?;
+        store.clear(Info::new()).await?;
+        store.merge("dev", Info::new()).await?.unwrap();
+        store.merge_into("dev", "main", Info::new()).await?.unwrap();
+        store.merge_tree(&key, &tree, Info::new()).await?.unwrap();
+        store.import(b"slice").await?;
+        client.set_current_branch("dev").await?;
+        assert_eq!(store.generation(), 13);
+
+        // Reads don't change it
+        store.find::<i64>(&key).await?;
+        assert_eq!(store.generation(), 13);
+        client.close().await
+    }
+
+    #[tokio::test]
+    async fn test_set_tree_in_parts() -> std::io::Result<()> {
+        // Server that numbers the trees returned by `tree.add_tree`, recording the keys added
+        // and the tree that is finally set
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut id = 0;
+        let server = MockServer::new(move |command, data| match command {
+            "tree.add_tree" => {
+                let (parts, key, _): (Tree<Bytes, Blake2b>, Key, Tree<Bytes, Blake2b>) =
+                    crate::r#type::decode_slice(data).unwrap();
+                let _ = tx.send(format!("{:?} {}", parts, key.to_string()));
+                id += 1;
+                MockServer::ok(Tree::<Bytes, Blake2b>::Id(id))
+            }
+            "store.set_tree" => {
+                let (key, _, tree): (Key, Info, Tree<Bytes, Blake2b>) =
+                    crate::r#type::decode_slice(data).unwrap();
+                let _ = tx.send(format!("set {} {:?}", key.to_string(), tree));
+                MockServer::ok(())
+            }
+            _ => panic!("Unexpected command: {}", command),
+        })
+        .await?;
+        let addr = server.addr();
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        let store = client.store();
+        let tree = TreeBuilder::new()
+            .add(Key::new(["a", "x"]), Bytes::from(vec![0; 8]))
+            .add(Key::new(["a", "y"]), Bytes::from(vec![0; 8]))
+            .add(Key::new(["b"]), Bytes::from(vec![0; 8]))
+            .build()?;
+        let root = Key::new(["root"]);
+
+        // Small trees are sent in a single request
+        store
+            .set_tree_in_parts(&root, &tree, Info::new(), 1024)
+            .await?;
+        let set = rx.recv().await.unwrap();
+        assert!(set.starts_with("set root Concrete"));
+
+        // `a` doesn't fit either, so it's split into `a/x` and `a/y`
+        store
+            .set_tree_in_parts(&root, &tree, Info::new(), 16)
+            .await?;
+        let mut requests = Vec::new();
+        for _ in 0..4 {
+            requests.push(rx.recv().await.unwrap());
+        }
+        assert_eq!(
+            requests,
+            [
+                "Concrete(Tree({})) a/x",
+                "Id(1) a/y",
+                "Id(2) b",
+                "set root Id(3)"
+            ]
+        );
+        assert_eq!(store.generation(), 2);
+        client.close().await
+    }
+
+    #[tokio::test]
+    async fn test_with_branch() -> std::io::Result<()> {
+        // Server with a current branch shared by every connection
+        let mut current = String::from("main");
+        let server = MockServer::new(move |command, data| match command {
+            "get_current_branch" => MockServer::ok(current.clone()),
+            "set_current_branch" => {
+                current = crate::r#type::decode_slice(data).unwrap();
+                MockServer::ok(())
+            }
+            _ => unreachable!(),
+        })
+        .await?;
+        let addr = server.addr();
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        let store = client.store();
+        let branch = store
+            .with_branch("dev", |store| async move {
+                store.client.get_current_branch().await
+            })
+            .await?;
+        assert_eq!(branch, "dev");
+        assert_eq!(client.get_current_branch().await?, "main");
+
+        let res: std::io::Result<()> = store
+            .with_branch("dev", |_| async {
+                Err(Error::new(ErrorKind::InvalidData, "failed"))
+            })
+            .await;
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(client.get_current_branch().await?, "main");
+
+        let task = tokio::spawn(async move {
+            let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+            client
+                .store()
+                .with_branch("dev", |_| async { panic!("inside with_branch") })
+                .await?;
+            std::io::Result::Ok(())
+        });
+        assert!(task.await.unwrap_err().is_panic());
+        assert_eq!(client.get_current_branch().await?, "main");
+        client.close().await
+    }
+
+    #[tokio::test]
+    async fn test_set_tree_checked() -> std::io::Result<()> {
+        // Server where the branch head never changes
+        let head = Commit::new(Blake2b::hash("node"), [], Info::new());
+        let server_head = head.clone();
+        let server = MockServer::new(move |command, data| {
+            assert_eq!(command, "store.set_tree_checked");
+            type Prefix = (Key, Info, Option<Commit<Blake2b>>);
+            let ((_, _, expected), _): (Prefix, Tree<Bytes, Blake2b>) =
+                crate::r#type::decode_slice(data).unwrap();
+            MockServer::ok(expected.as_ref() == Some(&server_head))
+        })
+        .await?;
+        let addr = server.addr();
+
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr).await?;
+        let store = client.store();
+        let key = Key::new(["a"]);
+        let tree = Tree::<Bytes, Blake2b>::empty();
+        assert!(
+            store
+                .set_tree_checked(&key, &tree, Some(&head), Info::new())
+                .await?
+        );
+        assert!(
+            !store
+                .set_tree_checked(&key, &tree, None, Info::new())
+                .await?
+        );
+        client.close().await
+    }
+
+    #[tokio::test]
+    async fn test_remove_batch() -> std::io::Result<()> {
+        // Server where only `a` is set and the root changes each time it's read, the first
+        // commit conflicts
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut roots = 0;
+        let mut conflict = true;
+        let server = MockServer::new(move |command, data| {
+            tx.send(command.to_string()).unwrap();
+            match command {
+                "store.find_tree" => {
+                    roots += 1;
+                    MockServer::ok(Some(Tree::<Bytes, Blake2b>::Id(roots)))
+                }
+                "tree.mem" => {
+                    let (_, key): (Tree<Bytes, Blake2b>, Key) =
+                        crate::r#type::decode_slice(data).unwrap();
+                    MockServer::ok(key == Key::new(["a"]))
+                }
+                "tree.mem_tree" => MockServer::ok(false),
+                "tree.remove" => MockServer::ok(Tree::<Bytes, Blake2b>::Id(0)),
+                "store.test_and_set_tree" => {
+                    type Trees = (
+                        Option<Tree<Bytes<'static>, Blake2b>>,
+                        Option<Tree<Bytes<'static>, Blake2b>>,
+                    );
+                    let (key, _, (test, set)): (Key, Info, Trees) =
+                        crate::r#type::decode_slice(data).unwrap();
+                    assert!(key.is_empty());
+                    assert_eq!(test, Some(Tree::Id(roots)));
+                    assert_eq!(set, Some(Tree::Id(0)));
+                    let ok = !conflict;
+                    conflict = false;
+                    MockServer::ok(ok)
+                }
+                _ => unreachable!(),
+            }
+        })
+        .await?;
+
+        let client = server.client::<Bytes, Blake2b>().await?;
+        let store = client.store();
+        let (a, missing) = (Key::new(["a"]), Key::new(["missing"]));
+        let mut commands = || {
+            std::iter::from_fn(|| rx.try_recv().ok())
+                .filter(|c| c.starts_with("store."))
+                .collect::<Vec<_>>()
+        };
+
+        // The root is read again after the conflict
+        store
+            .remove_batch(&[a, missing.clone()], Info::new())
+            .await?;
+        assert_eq!(
+            commands(),
+            [
+                "store.find_tree",
+                "store.test_and_set_tree",
+                "store.find_tree",
+                "store.test_and_set_tree"
+            ]
+        );
+
+        // Nothing is committed when none of the keys are set
+        store.remove_batch(&[missing], Info::new()).await?;
+        assert_eq!(commands(), ["store.find_tree"]);
+        client.close().await
+    }
+
+    #[tokio::test]
+    async fn test_client() -> std::io::Result<()> {
+        #[cfg(feature = "testutil")]
+        let (client, _server) = match super::testutil::spawn::<Bytes, Blake2b>().await {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Server error: {:?}", e);
+                return skip();
+            }
+        };
+        #[cfg(not(feature = "testutil"))]
+        let client = match Client::<Tcp, Bytes, Blake2b>::new("127.0.0.1:9181").await {
+            Ok(c) => c,
+            Err(e) => {
                 eprintln!("Server error: {:?}", e);
                 return skip();
             }
@@ -334,7 +3313,33 @@ mod tests {
             .await?;
         let s: Option<String> = store.find(&key).await?;
         assert_eq!(s, Some("testing".to_string()));
+        let (_, children) = store
+            .find_tree_with_children::<Bytes>(&Key::new(["a", "b", "c"]))
+            .await?
+            .unwrap();
+        assert_eq!(children, vec![("d".to_string(), Kind::Contents)]);
+        let mut buf = Vec::new();
+        assert!(store.find_to_writer(&key, &mut buf).await?);
+        assert_eq!(buf.as_slice(), b"testing");
+        let missing = Key::new(["missing"]);
+        let many: Vec<Option<String>> = store.find_many(&[key.clone(), missing]).await?;
+        assert_eq!(many, vec![Some("testing".to_string()), None]);
+        store.set(&key, Bytes::empty(), Info::new()).await?;
+        assert_eq!(store.find::<Bytes>(&key).await?, Some(Bytes::empty()));
         store.remove(&key, Info::new()).await?;
+        store
+            .remove_batch(&[key.clone(), Key::new(["missing"])], Info::new())
+            .await?;
+        assert!(!store.mem::<Bytes>(&key).await?);
+
+        let store = store.with_info(Info::new().with_message("default info"));
+        store.put(&key, Bytes::from("testing".as_bytes())).await?;
+        let info = store.head_info().await?.unwrap();
+        assert_eq!(info.message(), "default info");
+        let branch = client.get_current_branch().await?;
+        assert_eq!(store.branch_head(&branch).await?, store.head().await?);
+        assert!(store.branch_head("missing-branch").await?.is_none());
+        store.delete(&key).await?;
 
         let tree = Tree::<Bytes, Blake2b>::empty();
         println!("{:?}", tree);
@@ -349,6 +3354,14 @@ mod tests {
             let key1 = Key::new(["key1"]);
             let t = t.add(&client, &key1, &b).await?;
 
+            let paths = t.paths(&client).await?;
+            assert!(paths.contains(&key) && paths.contains(&key1));
+
+            let c = t.to_concrete(&client).await?;
+            assert!(c.mem_step("key1") && c.mem_tree_step("a"));
+            let uploaded = Tree::of_concrete(&client, c).await?;
+            assert!(uploaded.mem(&client, &key1).await?);
+
             let x = t.find(&client, &key1).await?;
             assert!(b.as_ref() == x.unwrap().as_ref());
 
@@ -356,7 +3369,13 @@ mod tests {
             let y = t.find_tree(&client, &key2).await?;
             assert!(y.is_none());
 
-            let t = t.remove(&client, &key1).await?;
+            let t = t
+                .update(&client, &key1, |x| x.map(|x| x.concat(&b)))
+                .await?;
+            let x = t.find(&client, &key1).await?;
+            assert!(x.unwrap().as_ref() == b"testing123testing123");
+
+            let t = t.update(&client, &key1, |_| None).await?;
             let x = t.find(&client, &key1).await?;
             assert!(x.is_none());
         }
@@ -0,0 +1,228 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, Error, ErrorKind};
+use tokio::net::{TcpStream, ToSocketAddrs, UnixStream};
+use tokio::task::JoinSet;
+
+use crate::client::Client;
+use crate::{Hash, KeyLimits, Type};
+
+/// How long to wait for a connection attempt before starting one to the next address, the
+/// value recommended by RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+type PreHandshakeFuture<Socket> = Pin<Box<dyn Future<Output = std::io::Result<Socket>> + Send>>;
+
+type PreHandshake<Socket> = Box<dyn FnOnce(Socket) -> PreHandshakeFuture<Socket> + Send>;
+
+/// Used to configure a `Client` before connecting, created using `Client::builder`
+pub struct ClientBuilder<Socket, C: Type, H: Hash> {
+    pre_handshake: Option<PreHandshake<Socket>>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    buffers: Option<(usize, usize)>,
+    branch: Option<String>,
+    flush_every_n: Option<usize>,
+    key_limits: Option<KeyLimits>,
+    _t: std::marker::PhantomData<(C, H)>,
+}
+
+impl<Socket: Unpin + AsyncRead + AsyncWrite, C: Type, H: Hash> ClientBuilder<Socket, C, H> {
+    pub(crate) fn new() -> Self {
+        ClientBuilder {
+            pre_handshake: None,
+            read_timeout: None,
+            write_timeout: None,
+            buffers: None,
+            branch: None,
+            flush_every_n: None,
+            key_limits: None,
+            _t: std::marker::PhantomData,
+        }
+    }
+
+    /// Set both the read and write timeout, see `Client::set_read_timeout`. The timeouts also
+    /// apply to the handshake.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.read_timeout(timeout).write_timeout(timeout)
+    }
+
+    /// Set the read timeout, see `Client::set_read_timeout`
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the write timeout, see `Client::set_write_timeout`
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the capacity of the read and write buffers used for the connection, by default each
+    /// one is 8KiB. Larger buffers mean fewer system calls when transferring large values.
+    pub fn buffers(mut self, read: usize, write: usize) -> Self {
+        self.buffers = Some((read, write));
+        self
+    }
+
+    /// Switch to `branch` after connecting, instead of using the server's current branch
+    pub fn branch(mut self, branch: impl Into<String>) -> Self {
+        self.branch = Some(branch.into());
+        self
+    }
+
+    /// Run `f` on the newly opened socket before the Irmin handshake, this can be used to send
+    /// an authentication token or negotiate with a proxy. The socket is unbuffered, so `f` should
+    /// avoid reading past the end of its own exchange. `f` returns the socket that the handshake
+    /// is performed on.
+    pub fn pre_handshake<F, Fut>(mut self, f: F) -> Self
+    where
+        F: FnOnce(Socket) -> Fut + Send + 'static,
+        Fut: Future<Output = std::io::Result<Socket>> + Send + 'static,
+    {
+        self.pre_handshake = Some(Box::new(move |socket| Box::pin(f(socket))));
+        self
+    }
+
+    /// Set the number of pipelined requests written before flushing, see
+    /// `Client::set_flush_every_n`
+    pub fn flush_every_n(mut self, n: usize) -> Self {
+        self.flush_every_n = Some(n);
+        self
+    }
+
+    /// Set the limits used when decoding keys, see `Client::set_key_limits`
+    pub fn key_limits(mut self, limits: KeyLimits) -> Self {
+        self.key_limits = Some(limits);
+        self
+    }
+
+    /// Create a client using a socket that is already connected to the server. The socket can
+    /// be any stream, so TLS is used by establishing the TLS session first and passing the
+    /// wrapped stream here, the builder has no TLS options of its own.
+    pub async fn build(self, socket: Socket) -> std::io::Result<Client<Socket, C, H>> {
+        let socket = match self.pre_handshake {
+            Some(f) => f(socket).await?,
+            None => socket,
+        };
+        let client = Client::from_stream(socket, self.buffers);
+        client.set_read_timeout(self.read_timeout)?;
+        client.set_write_timeout(self.write_timeout)?;
+        if let Some(n) = self.flush_every_n {
+            client.set_flush_every_n(n);
+        }
+        if let Some(limits) = self.key_limits {
+            client.set_key_limits(limits);
+        }
+        client.do_handshake().await?;
+        if let Some(branch) = self.branch {
+            client.set_current_branch(branch).await?;
+        }
+        Ok(client)
+    }
+}
+
+impl<C: Type, H: Hash> ClientBuilder<TcpStream, C, H> {
+    /// Connect to a TCP server. When `s` resolves to several addresses they are tried using
+    /// happy eyeballs (RFC 8305): IPv6 and IPv4 addresses are alternated and a new attempt is
+    /// started every 250ms, or as soon as the previous one fails, until one of them connects, so
+    /// an unreachable address family doesn't delay connecting.
+    pub async fn connect(self, s: impl ToSocketAddrs) -> std::io::Result<Client<TcpStream, C, H>> {
+        let conn = happy_eyeballs(s).await?;
+        let endpoint = conn.peer_addr()?;
+        let mut client = self.build(conn).await?;
+        client.endpoint = Some(endpoint);
+        Ok(client)
+    }
+}
+
+impl<C: Type, H: Hash> ClientBuilder<UnixStream, C, H> {
+    /// Connect to a Unix socket
+    pub async fn connect(
+        self,
+        s: impl AsRef<std::path::Path>,
+    ) -> std::io::Result<Client<UnixStream, C, H>> {
+        let conn = UnixStream::connect(s).await?;
+        self.build(conn).await
+    }
+
+    /// Connect to a Unix socket in the Linux abstract namespace, `name` is the socket name
+    /// without the leading null byte
+    #[cfg(target_os = "linux")]
+    pub async fn connect_abstract(
+        self,
+        name: impl AsRef<[u8]>,
+    ) -> std::io::Result<Client<UnixStream, C, H>> {
+        use std::os::linux::net::SocketAddrExt;
+
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        let conn = std::os::unix::net::UnixStream::connect_addr(&addr)?;
+        conn.set_nonblocking(true)?;
+        self.build(UnixStream::from_std(conn)?).await
+    }
+}
+
+/// Connect to the first address that accepts a connection, see `ClientBuilder::connect`
+async fn happy_eyeballs(s: impl ToSocketAddrs) -> std::io::Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host(s).await?.collect();
+    let family = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "No addresses to connect to",
+            ))
+        }
+    };
+
+    // Alternate address families, starting with the family of the first address
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == family);
+    let mut pending = VecDeque::new();
+    while !first.is_empty() || !second.is_empty() {
+        pending.extend(first.pop_front());
+        pending.extend(second.pop_front());
+    }
+
+    // A new attempt is started each time the delay since the last one has passed, or as soon
+    // as an attempt fails
+    let mut attempts = JoinSet::new();
+    let mut errors = Vec::new();
+    let mut kind = ErrorKind::InvalidInput;
+    let mut delay = std::pin::pin!(tokio::time::sleep(CONNECTION_ATTEMPT_DELAY));
+    loop {
+        match pending.pop_front() {
+            Some(addr) => {
+                attempts.spawn(async move { (addr, TcpStream::connect(addr).await) });
+                delay
+                    .as_mut()
+                    .reset(tokio::time::Instant::now() + CONNECTION_ATTEMPT_DELAY);
+            }
+            None if attempts.is_empty() => break,
+            None => (),
+        }
+
+        tokio::select! {
+            res = attempts.join_next(), if !attempts.is_empty() => match res {
+                Some(Ok((_, Ok(conn)))) => return Ok(conn),
+                Some(Ok((addr, Err(e)))) => {
+                    kind = e.kind();
+                    errors.push(format!("{}: {}", addr, e));
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => (),
+            },
+            _ = &mut delay, if !pending.is_empty() => (),
+        }
+    }
+
+    Err(Error::new(
+        kind,
+        format!("Unable to connect to any address ({})", errors.join(", ")),
+    ))
+}
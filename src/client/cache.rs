@@ -0,0 +1,426 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::client::{Client, Store};
+use crate::{Hash, Info, Key, Kind, Tree, Type};
+
+/// Default time a checked branch head is trusted for, see `CachingStore::with_max_age`
+const HEAD_MAX_AGE: Duration = Duration::from_secs(1);
+
+struct Cache<T, H> {
+    generation: u64,
+    node: Option<H>,
+    checked: Option<Instant>,
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<Key, (Option<T>, u64)>,
+    order: BTreeMap<u64, Key>,
+}
+
+impl<T: Clone, H: PartialEq> Cache<T, H> {
+    fn get(&mut self, key: &Key) -> Option<Option<T>> {
+        let tick = self.tick;
+        let (value, last) = self.entries.get_mut(key)?;
+        self.order.remove(last);
+        self.order.insert(tick, key.clone());
+        *last = tick;
+        self.tick += 1;
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: Key, value: Option<T>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if let Some((_, last)) = self.entries.insert(key.clone(), (value, self.tick)) {
+            self.order.remove(&last);
+        }
+        self.order.insert(self.tick, key);
+        self.tick += 1;
+
+        while self.entries.len() > self.capacity {
+            let (_, oldest) = self.order.pop_first().unwrap();
+            self.entries.remove(&oldest);
+        }
+    }
+
+    /// Remove every entry at, above or below `key`. Writing below a key that was set to a value
+    /// turns it into a tree, so entries for the ancestors of `key` are stale too.
+    fn invalidate(&mut self, key: &Key) {
+        let order = &mut self.order;
+        self.entries.retain(|k, (_, tick)| {
+            let keep = !k.starts_with(key) && !key.starts_with(k);
+            if !keep {
+                order.remove(tick);
+            }
+            keep
+        });
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    /// Record the root tree of the branch head, removing every entry if it moved
+    fn checked(&mut self, node: Option<H>) {
+        if self.node != node {
+            self.clear();
+            self.node = node;
+        }
+        self.checked = Some(Instant::now());
+    }
+}
+
+/// Wrapper around `Store` that keeps the results of `find` in a bounded LRU cache. Entries are
+/// only valid for the root tree of the branch head they were read from: the head is checked on a
+/// miss, when the last check is older than the max age, or when `refresh` is called, and the
+/// cache is cleared if it moved. Between checks cached values are used without a request, so
+/// writes made by other clients can be missed for up to the max age. Writes made through the
+/// wrapper invalidate the entries they affect right away, any other write on the same client
+/// clears the cache.
+pub struct CachingStore<'a, Socket, Contents: Type, H: Hash> {
+    store: Store<'a, Socket, Contents, H>,
+    cache: std::sync::Mutex<Cache<Contents, H>>,
+    max_age: Duration,
+}
+
+impl<'a, Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type + Clone, H: Hash>
+    CachingStore<'a, Socket, Contents, H>
+{
+    /// Wrap `store`, caching at most `capacity` values
+    pub fn new(store: Store<'a, Socket, Contents, H>, capacity: usize) -> Self {
+        let generation = store.generation();
+        CachingStore {
+            store,
+            cache: std::sync::Mutex::new(Cache {
+                generation,
+                node: None,
+                checked: None,
+                capacity,
+                tick: 0,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+            }),
+            max_age: HEAD_MAX_AGE,
+        }
+    }
+
+    /// Set how long a checked branch head is trusted before `find` checks it again, one second
+    /// by default. With `Duration::ZERO` the head is checked by every `find`, so writes made by
+    /// other clients are never missed.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Access the underlying store, writes made directly using the store clear the cache
+    pub fn store(&self) -> &Store<'a, Socket, Contents, H> {
+        &self.store
+    }
+
+    /// Find a value in the store, using the cached value if the branch head hasn't moved since
+    /// it was fetched
+    pub async fn find(&self, key: &Key) -> std::io::Result<Option<Contents>> {
+        let generation = self.store.generation();
+        {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.generation != generation {
+                cache.clear();
+                cache.generation = generation;
+            }
+            let fresh = cache
+                .checked
+                .is_some_and(|checked| checked.elapsed() <= self.max_age);
+            if fresh {
+                if let Some(value) = cache.get(key) {
+                    return Ok(value);
+                }
+            }
+        }
+
+        // Check the head before reading the value, so a write made in between leaves the value
+        // newer than the recorded head and the next check clears it
+        self.refresh().await?;
+        if let Some(value) = self.cache.lock().unwrap().get(key) {
+            return Ok(value);
+        }
+
+        let value: Option<Contents> = self.store.find(key).await?;
+        let mut cache = self.cache.lock().unwrap();
+        if cache.generation == generation && self.store.generation() == generation {
+            cache.insert(key.clone(), value.clone());
+        }
+        Ok(value)
+    }
+
+    /// Request the branch head and clear the cache if its root tree changed since the last
+    /// check, used to pick up writes made by other clients before the max age has passed
+    pub async fn refresh(&self) -> std::io::Result<()> {
+        let node = self.store.head().await?.map(|commit| commit.node);
+        self.cache.lock().unwrap().checked(node);
+        Ok(())
+    }
+
+    /// Invalidate the entries affected by a write to `key` made through the wrapper, keeping the
+    /// rest of the cache if no other write happened since `generation` was read
+    fn written(&self, key: &Key, generation: u64) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.invalidate(key);
+        if cache.generation == generation && self.store.generation() == generation + 1 {
+            cache.generation += 1;
+        }
+    }
+
+    /// Set the value associated with a key
+    pub async fn set(&self, key: &Key, value: Contents, info: Info) -> std::io::Result<()> {
+        let generation = self.store.generation();
+        let res = self.store.set(key, value, info).await;
+        self.written(key, generation);
+        res
+    }
+
+    /// Set the tree associated with a key
+    pub async fn set_tree(
+        &self,
+        key: &Key,
+        tree: &Tree<Contents, H>,
+        info: Info,
+    ) -> std::io::Result<()> {
+        let generation = self.store.generation();
+        let res = self.store.set_tree(key, tree, info).await;
+        self.written(key, generation);
+        res
+    }
+
+    /// Remove the value associated with a key
+    pub async fn remove(&self, key: &Key, info: Info) -> std::io::Result<()> {
+        let generation = self.store.generation();
+        let res = self.store.remove(key, info).await;
+        self.written(key, generation);
+        res
+    }
+
+    /// Remove all cached values
+    pub fn clear_cache(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+}
+
+/// Wrapper around a `Tree` that remembers the subtrees and listings fetched from the server, so
+/// visiting the same key more than once only costs a single request. Trees are immutable, so the
+/// cache is valid for the lifetime of the handle; `add` and `remove` return a new handle with an
+/// empty cache.
+pub struct CachingTree<'a, Socket, Contents: Type, T: Type, H: Hash> {
+    client: &'a Client<Socket, Contents, H>,
+    tree: Tree<T, H>,
+    trees: std::sync::Mutex<HashMap<Key, Option<Tree<T, H>>>>,
+    lists: std::sync::Mutex<HashMap<Key, Vec<(String, Kind)>>>,
+}
+
+impl<'a, Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type, T: Type + Clone, H: Hash>
+    CachingTree<'a, Socket, Contents, T, H>
+{
+    /// Wrap `tree`, requests are made using `client`
+    pub fn new(client: &'a Client<Socket, Contents, H>, tree: Tree<T, H>) -> Self {
+        CachingTree {
+            client,
+            tree,
+            trees: std::sync::Mutex::new(HashMap::new()),
+            lists: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Access the underlying tree
+    pub fn tree(&self) -> &Tree<T, H> {
+        &self.tree
+    }
+
+    /// Unwrap the underlying tree, dropping the cache
+    pub fn into_tree(self) -> Tree<T, H> {
+        self.tree
+    }
+
+    /// Find tree in tree, using the cached subtree if `key` has already been fetched
+    pub async fn find_tree(&self, key: &Key) -> std::io::Result<Option<Tree<T, H>>> {
+        if let Some(tree) = self.trees.lock().unwrap().get(key) {
+            return Ok(tree.clone());
+        }
+
+        let tree = self.tree.find_tree(self.client, key).await?;
+        self.trees.lock().unwrap().insert(key.clone(), tree.clone());
+        Ok(tree)
+    }
+
+    /// List the steps directly under a key, using the cached listing if `key` has already been
+    /// listed
+    pub async fn list(&self, key: &Key) -> std::io::Result<Vec<(String, Kind)>> {
+        if let Some(list) = self.lists.lock().unwrap().get(key) {
+            return Ok(list.clone());
+        }
+
+        let list = self.tree.list(self.client, key).await?;
+        self.lists.lock().unwrap().insert(key.clone(), list.clone());
+        Ok(list)
+    }
+
+    /// Add value to tree, returning a new handle
+    pub async fn add(&self, key: &Key, value: &T) -> std::io::Result<Self> {
+        let tree = self.tree.add(self.client, key, value).await?;
+        Ok(CachingTree::new(self.client, tree))
+    }
+
+    /// Remove key from tree, returning a new handle
+    pub async fn remove(&self, key: &Key) -> std::io::Result<Self> {
+        let tree = self.tree.remove(self.client, key).await?;
+        Ok(CachingTree::new(self.client, tree))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::testutil::MockServer;
+    use crate::{Blake2b, Commit};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_lru() {
+        let mut cache: Cache<u8, crate::Blake2b> = Cache {
+            generation: 0,
+            node: None,
+            checked: None,
+            capacity: 2,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        };
+        let (a, b, c) = (Key::new(["a"]), Key::new(["b"]), Key::new(["b", "c"]));
+        cache.insert(a.clone(), Some(1));
+        cache.insert(b.clone(), None);
+        assert_eq!(cache.get(&a), Some(Some(1)));
+
+        cache.insert(c.clone(), Some(3));
+        assert_eq!(cache.get(&b), None);
+        assert_eq!(cache.get(&a), Some(Some(1)));
+        assert_eq!(cache.get(&c), Some(Some(3)));
+
+        cache.invalidate(&b);
+        assert_eq!(cache.get(&c), None);
+        assert_eq!(cache.entries.len(), cache.order.len());
+
+        // Ancestors of the invalidated key are removed, siblings are kept
+        cache.insert(b.clone(), Some(2));
+        cache.insert(a.clone(), Some(1));
+        cache.invalidate(&c);
+        assert_eq!(cache.get(&b), None);
+        assert_eq!(cache.get(&a), Some(Some(1)));
+        assert_eq!(cache.entries.len(), cache.order.len());
+    }
+
+    #[tokio::test]
+    async fn test_caching_store() -> std::io::Result<()> {
+        // Server where every key is set to `value`, counting `store.find` and `store.head`
+        // requests. The head only moves when `node` is changed, like a write by another client.
+        let (finds, heads) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let node = Arc::new(std::sync::Mutex::new("node"));
+        let (f, h, n) = (finds.clone(), heads.clone(), node.clone());
+        let server = MockServer::new(move |command, _| match command {
+            "store.find" => {
+                f.fetch_add(1, Ordering::Relaxed);
+                MockServer::ok(Some("value"))
+            }
+            "store.set" | "store.remove" => MockServer::ok(()),
+            "store.head" => {
+                h.fetch_add(1, Ordering::Relaxed);
+                let node = Blake2b::hash(*n.lock().unwrap());
+                MockServer::ok(Some(Commit::new(node, vec![], Info::new())))
+            }
+            _ => unreachable!(),
+        })
+        .await?;
+        let client = server.client::<String, Blake2b>().await?;
+        let store = CachingStore::new(client.store(), 8).with_max_age(Duration::from_secs(3600));
+        let (a, b) = (Key::new(["a"]), Key::new(["b"]));
+        let counts = || (finds.load(Ordering::Relaxed), heads.load(Ordering::Relaxed));
+
+        // Each miss checks the head, hits don't make any requests
+        store.find(&a).await?;
+        store.find(&a).await?;
+        store.find(&b).await?;
+        assert_eq!(counts(), (2, 2));
+
+        // Writes made through the wrapper only invalidate the key that was written
+        store.set(&a, String::new(), Info::new()).await?;
+        store.find(&a).await?;
+        store.find(&b).await?;
+        assert_eq!(counts(), (3, 3));
+
+        // Writing below a cached key invalidates it, since it's now a tree
+        let a_b = Key::new(["a", "b"]);
+        store.set(&a_b, String::new(), Info::new()).await?;
+        store.find(&a).await?;
+        store.find(&b).await?;
+        assert_eq!(counts(), (4, 4));
+
+        // Writes made directly using the store clear the cache
+        store.store().remove(&b, Info::new()).await?;
+        store.find(&a).await?;
+        assert_eq!(counts(), (5, 5));
+
+        // Refreshing keeps the cache while the head is unchanged, and clears it once the head
+        // has been moved by another client
+        store.refresh().await?;
+        store.find(&a).await?;
+        assert_eq!(counts(), (5, 6));
+        *node.lock().unwrap() = "other";
+        store.find(&a).await?;
+        assert_eq!(counts(), (5, 6));
+        store.refresh().await?;
+        store.find(&a).await?;
+        assert_eq!(counts(), (6, 8));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_caching_store_max_age() -> std::io::Result<()> {
+        // Server where every key is set to `value`, the head moves each time it's requested
+        // after `moved` is set
+        let finds = Arc::new(AtomicUsize::new(0));
+        let moved = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let (f, m) = (finds.clone(), moved.clone());
+        let mut n = 0;
+        let server = MockServer::new(move |command, _| match command {
+            "store.find" => {
+                f.fetch_add(1, Ordering::Relaxed);
+                MockServer::ok(Some("value"))
+            }
+            "store.head" => {
+                if m.load(Ordering::Relaxed) {
+                    n += 1;
+                }
+                let node = Blake2b::hash(n.to_string());
+                MockServer::ok(Some(Commit::new(node, vec![], Info::new())))
+            }
+            _ => unreachable!(),
+        })
+        .await?;
+        let client = server.client::<String, Blake2b>().await?;
+        let store = CachingStore::new(client.store(), 8).with_max_age(Duration::ZERO);
+        let a = Key::new(["a"]);
+        let finds = || finds.load(Ordering::Relaxed);
+
+        // Every find checks the head, so the value is only fetched again once it moves
+        store.find(&a).await?;
+        store.find(&a).await?;
+        assert_eq!(finds(), 1);
+        moved.store(true, Ordering::Relaxed);
+        store.find(&a).await?;
+        assert_eq!(finds(), 2);
+        Ok(())
+    }
+}
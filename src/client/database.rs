@@ -0,0 +1,97 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::client::{Client, Store};
+use crate::{Hash, Info, Key, Type};
+
+/// Simplified interface to a single branch, for applications that only need to read and write
+/// values at `/`-separated paths. Commits are created using a fixed author and a message
+/// describing each change.
+pub struct Database<Socket, Contents: Type, H: Hash> {
+    client: Client<Socket, Contents, H>,
+    branch: String,
+    author: String,
+}
+
+impl<Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type, H: Hash>
+    Database<Socket, Contents, H>
+{
+    /// Use `branch` as the current branch of `client` and wrap it
+    pub async fn open(
+        client: Client<Socket, Contents, H>,
+        branch: impl Into<String>,
+    ) -> std::io::Result<Self> {
+        let branch = branch.into();
+        client.set_current_branch(&branch).await?;
+        Ok(Database {
+            client,
+            branch,
+            author: Info::new().author().into_owned(),
+        })
+    }
+
+    /// Set the author used for new commits
+    pub fn with_author(mut self, author: impl Into<String>) -> Self {
+        self.author = author.into();
+        self
+    }
+
+    /// Get the branch the database reads from and writes to
+    pub fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    /// Access the underlying client
+    pub fn client(&self) -> &Client<Socket, Contents, H> {
+        &self.client
+    }
+
+    /// Unwrap the underlying client
+    pub fn into_client(self) -> Client<Socket, Contents, H> {
+        self.client
+    }
+
+    /// Close the underlying client, see `Client::close`
+    pub async fn close(self) -> std::io::Result<()> {
+        self.client.close().await
+    }
+
+    fn store(&self) -> Store<'_, Socket, Contents, H> {
+        self.client.store()
+    }
+
+    fn info(&self, message: String) -> Info {
+        Info::new()
+            .with_author(self.author.as_str())
+            .with_message(message)
+    }
+
+    /// Get the value at `path`, returns `None` if it isn't set
+    pub async fn get(&self, path: impl AsRef<str>) -> std::io::Result<Option<Contents>> {
+        self.store().find(&Key::from_string(path)).await
+    }
+
+    /// Set the value at `path`
+    pub async fn put(&self, path: impl AsRef<str>, value: Contents) -> std::io::Result<()> {
+        let key = Key::from_string(path);
+        let info = self.info(format!("put {}", key.to_string()));
+        self.store().set(&key, value, info).await
+    }
+
+    /// Remove the value or directory at `path`
+    pub async fn delete(&self, path: impl AsRef<str>) -> std::io::Result<()> {
+        let key = Key::from_string(path);
+        let info = self.info(format!("delete {}", key.to_string()));
+        self.store().remove(&key, info).await
+    }
+
+    /// List the names directly under `path`, returns an empty list if `path` isn't a directory
+    pub async fn list(&self, path: impl AsRef<str>) -> std::io::Result<Vec<String>> {
+        let key = Key::from_string(path);
+        let tree = match self.store().find_tree::<Contents>(&key).await? {
+            Some(tree) => tree,
+            None => return Ok(Vec::new()),
+        };
+        let items = tree.list(&self.client, &Key::empty()).await?;
+        Ok(items.into_iter().map(|(step, _)| step).collect())
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+
+use tokio::io::{AsyncRead, AsyncWrite, Error, ErrorKind};
+
+use crate::client::Store;
+use crate::{Hash, Key, Kind, Type};
+
+/// Number of children requested in each page
+const PAGE_SIZE: usize = 256;
+
+/// Where the next page of children comes from
+enum Cursor {
+    /// The first page hasn't been requested yet
+    Start,
+
+    /// The server returned a token for the next page
+    Token(String),
+
+    /// Every child has been received
+    Done,
+}
+
+/// Lists the children of a key one page at a time, created using `Store::list_stream`.
+///
+/// Pages are requested using `store.list_page`, which takes the key, the token returned with
+/// the previous page (`None` for the first page) and the maximum number of children to return.
+/// It responds with the children and the token for the next page, `None` once there are no more
+/// children. Tokens are opaque and only valid for the branch head they were issued for. Servers
+/// without cursored listing are handled by listing every child in a single request and yielding
+/// them from memory.
+pub struct ListStream<'a, Socket, Contents: Type, H: Hash> {
+    store: Store<'a, Socket, Contents, H>,
+    key: Key,
+    page_size: usize,
+    cursor: Cursor,
+    buffer: VecDeque<(String, Kind)>,
+}
+
+impl<'a, Socket: Unpin + AsyncRead + AsyncWrite, Contents: Type, H: Hash>
+    ListStream<'a, Socket, Contents, H>
+{
+    pub(crate) fn new(store: Store<'a, Socket, Contents, H>, key: &Key) -> Self {
+        ListStream {
+            store,
+            key: key.clone(),
+            page_size: PAGE_SIZE,
+            cursor: Cursor::Start,
+            buffer: VecDeque::new(),
+        }
+    }
+
+    /// Set the maximum number of children requested at once
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        assert!(page_size > 0, "Page size must be greater than zero");
+        self.page_size = page_size;
+        self
+    }
+
+    /// Get the next child along with the kind of node it points to, returns `None` once every
+    /// child has been returned. A new page is only requested when the previous one is used up.
+    pub async fn next(&mut self) -> Option<std::io::Result<(Key, Kind)>> {
+        while self.buffer.is_empty() {
+            let token = match std::mem::replace(&mut self.cursor, Cursor::Done) {
+                Cursor::Start => None,
+                Cursor::Token(token) => Some(token),
+                Cursor::Done => return None,
+            };
+            let first = token.is_none();
+            let (items, next) = match self.page(token).await {
+                Ok(page) => page,
+                Err(e) if first && super::is_unknown_command(&e) => match self.list_all().await {
+                    Ok(items) => (items, None),
+                    Err(e) => return Some(Err(e)),
+                },
+                Err(e) => return Some(Err(e)),
+            };
+            if let Some(next) = next {
+                self.cursor = Cursor::Token(next);
+            }
+            self.buffer.extend(items);
+        }
+
+        let (step, kind) = self.buffer.pop_front()?;
+        let mut key = self.key.clone();
+        key.push(step);
+        Some(Ok((key, kind)))
+    }
+
+    /// Collect the remaining children
+    pub async fn collect(mut self) -> std::io::Result<Vec<(Key, Kind)>> {
+        let mut items = Vec::new();
+        while let Some(item) = self.next().await {
+            items.push(item?);
+        }
+        Ok(items)
+    }
+
+    async fn page(
+        &self,
+        token: Option<String>,
+    ) -> std::io::Result<(Vec<(String, Kind)>, Option<String>)> {
+        let (items, next): (Vec<(String, Kind)>, Option<String>) = self
+            .store
+            .client
+            .call("store.list_page", (&self.key, token, self.page_size))
+            .await?;
+        if items.is_empty() && next.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Server returned an empty page with a continuation token",
+            ));
+        }
+        Ok((items, next))
+    }
+
+    async fn list_all(&self) -> std::io::Result<Vec<(String, Kind)>> {
+        match self.store.find_tree::<Contents>(&self.key).await? {
+            Some(tree) => tree.list(self.store.client, &Key::empty()).await,
+            None => Ok(Vec::new()),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::client::{Client, Tcp};
+use crate::{Hash, Type};
+
+type ConnectFuture<Socket, C, H> =
+    Pin<Box<dyn Future<Output = std::io::Result<Client<Socket, C, H>>> + Send>>;
+
+type Connect<Socket, C, H> = Box<dyn Fn() -> ConnectFuture<Socket, C, H> + Send + Sync>;
+
+/// A pool of reusable client connections, new connections are opened when there are no idle ones
+/// available and at most `max_idle` connections are kept around for reuse
+pub struct Pool<Socket, C: Type, H: Hash> {
+    connect: Connect<Socket, C, H>,
+    idle_tx: mpsc::Sender<Client<Socket, C, H>>,
+    idle_rx: Mutex<mpsc::Receiver<Client<Socket, C, H>>>,
+    max_idle: usize,
+}
+
+/// A `Client` borrowed from a `Pool`, the connection is returned to the pool when this is dropped
+pub struct Pooled<Socket, C: Type, H: Hash> {
+    client: Option<Client<Socket, C, H>>,
+    pool: mpsc::Sender<Client<Socket, C, H>>,
+}
+
+impl<Socket: Unpin + AsyncRead + AsyncWrite, C: Type, H: Hash> Pool<Socket, C, H> {
+    /// Create a new pool that opens connections using `connect`
+    pub fn new<F, Fut>(max_idle: usize, connect: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::io::Result<Client<Socket, C, H>>> + Send + 'static,
+    {
+        assert!(max_idle > 0, "Pool must allow at least one idle connection");
+        let (idle_tx, idle_rx) = mpsc::channel(max_idle);
+        Pool {
+            connect: Box::new(move || Box::pin(connect())),
+            idle_tx,
+            idle_rx: Mutex::new(idle_rx),
+            max_idle,
+        }
+    }
+
+    /// Get an idle connection from the pool, or open a new one if none are available
+    pub async fn get(&self) -> std::io::Result<Pooled<Socket, C, H>> {
+        let mut idle = self.idle_rx.lock().await;
+        while let Ok(client) = idle.try_recv() {
+            if client.is_connected() {
+                return Ok(Pooled {
+                    client: Some(client),
+                    pool: self.idle_tx.clone(),
+                });
+            }
+            client.discard();
+        }
+        drop(idle);
+
+        let client = (self.connect)().await?;
+        Ok(Pooled {
+            client: Some(client),
+            pool: self.idle_tx.clone(),
+        })
+    }
+
+    /// Number of idle connections waiting to be reused
+    pub fn idle(&self) -> usize {
+        self.max_idle - self.idle_tx.capacity()
+    }
+}
+
+impl<C: Type + Send + Sync + 'static, H: Hash + Send + Sync + 'static> Pool<Tcp, C, H> {
+    /// Create a new pool of connections to a TCP server
+    pub fn tcp(addr: SocketAddr, max_idle: usize) -> Self {
+        Pool::new(max_idle, move || Client::<Tcp, C, H>::new(addr))
+    }
+}
+
+impl<Socket, C: Type, H: Hash> std::ops::Deref for Pooled<Socket, C, H> {
+    type Target = Client<Socket, C, H>;
+
+    fn deref(&self) -> &Self::Target {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl<Socket, C: Type, H: Hash> Drop for Pooled<Socket, C, H> {
+    fn drop(&mut self) {
+        // `try_send` never blocks, when the pool already has enough idle connections (or has been
+        // dropped) the client is closed instead
+        if let Some(client) = self.client.take() {
+            if !client.is_connected() {
+                client.discard();
+            } else if let Err(e) = self.pool.try_send(client) {
+                e.into_inner().discard();
+            }
+        }
+    }
+}
+
+impl<Socket, C: Type, H: Hash> Drop for Pool<Socket, C, H> {
+    fn drop(&mut self) {
+        let idle = self.idle_rx.get_mut();
+        while let Ok(client) = idle.try_recv() {
+            client.discard();
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::io::Write;
+use std::pin::Pin;
+use std::sync::{Mutex, OnceLock};
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::client::Client;
+use crate::{irmin, Hash, Type};
+
+/// A single request and the response it received, as written by `Client::with_recorder`
+#[derive(Debug, Clone, PartialEq, Eq, Type)]
+pub struct Record {
+    pub command: String,
+
+    /// The encoded request message, without the length prefix
+    pub request: Vec<u8>,
+
+    /// The raw response, including the status byte and length prefix
+    pub response: Vec<u8>,
+}
+
+/// Splits the bytes written by a client into requests, each one is a command line followed by a
+/// length-prefixed message
+#[derive(Default)]
+struct Requests(Vec<u8>);
+
+impl Requests {
+    fn next(&mut self) -> Option<(String, Vec<u8>)> {
+        let line = self.0.iter().position(|&b| b == b'\n')? + 1;
+        let start = line + 8;
+        let len = i64::from_be_bytes(self.0.get(line..start)?.try_into().unwrap());
+        let end = start.checked_add(len.try_into().ok()?)?;
+        let msg = self.0.get(start..end)?.to_vec();
+        let command = String::from_utf8_lossy(&self.0[..line - 1]).into_owned();
+        self.0.drain(..end);
+        Some((command, msg))
+    }
+}
+
+/// Splits the bytes read by a client into responses, each one is a status byte followed by a
+/// length-prefixed message
+#[derive(Default)]
+struct Responses(Vec<u8>);
+
+impl Responses {
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let len = i64::from_be_bytes(self.0.get(1..9)?.try_into().unwrap());
+        let end = 9usize.checked_add(len.try_into().ok()?)?;
+        self.0.get(..end)?;
+        Some(self.0.drain(..end).collect())
+    }
+}
+
+struct Recording {
+    out: Box<dyn Write + Send>,
+    requests: Requests,
+    responses: Responses,
+    pending: VecDeque<(String, Vec<u8>)>,
+}
+
+/// Destination for the records written by `Client::with_recorder`, when no writer has been set
+/// recording only costs a single atomic load per read or write
+#[derive(Default)]
+pub(crate) struct Recorder(OnceLock<Mutex<Recording>>);
+
+impl Recorder {
+    pub(crate) fn set(&self, out: Box<dyn Write + Send>) {
+        let mut out = Some(out);
+        let recording = self.0.get_or_init(|| {
+            Mutex::new(Recording {
+                out: out.take().unwrap(),
+                requests: Requests::default(),
+                responses: Responses::default(),
+                pending: VecDeque::new(),
+            })
+        });
+        if let Some(out) = out {
+            recording.lock().unwrap().out = out;
+        }
+    }
+
+    /// Called with the bytes written to the socket
+    pub(crate) fn sent(&self, data: &[u8]) {
+        if let Some(recording) = self.0.get() {
+            let mut recording = recording.lock().unwrap();
+            recording.requests.0.extend_from_slice(data);
+            while let Some(request) = recording.requests.next() {
+                recording.pending.push_back(request);
+            }
+        }
+    }
+
+    /// Called with the bytes read from the socket, a record is written once a complete response
+    /// has been received
+    pub(crate) fn received(&self, data: &[u8]) {
+        if let Some(recording) = self.0.get() {
+            let recording = &mut *recording.lock().unwrap();
+            recording.responses.0.extend_from_slice(data);
+            while let Some(response) = recording.responses.next() {
+                let (command, request) = match recording.pending.pop_front() {
+                    Some(x) => x,
+                    None => break,
+                };
+                let record = Record {
+                    command,
+                    request,
+                    response,
+                };
+                let _ = record.encode_bin(&mut recording.out);
+                let _ = recording.out.flush();
+            }
+        }
+    }
+}
+
+/// Socket that answers requests using the responses from a recorded session instead of a
+/// server. Requests must be sent in the same order as they were recorded, only the commands are
+/// compared since messages often contain timestamps. Once every response has been used reads
+/// return EOF.
+pub struct Replay {
+    records: VecDeque<Record>,
+    handshake: Option<Vec<u8>>,
+    requests: Requests,
+    output: VecDeque<u8>,
+}
+
+/// A `Client` that replays a recorded session
+pub type ReplayClient<C, H> = Client<Replay, C, H>;
+
+impl Replay {
+    pub fn new(records: impl IntoIterator<Item = Record>) -> Replay {
+        Replay {
+            records: records.into_iter().collect(),
+            handshake: Some(Vec::new()),
+            requests: Requests::default(),
+            output: VecDeque::new(),
+        }
+    }
+
+    /// Load the records written to a file by `Client::with_recorder`
+    pub fn open(path: impl AsRef<std::path::Path>) -> std::io::Result<Replay> {
+        let data = std::fs::read(path)?;
+        let mut src = data.as_slice();
+        let mut records = Vec::new();
+        while !src.is_empty() {
+            records.push(Record::decode_bin(&mut src)?);
+        }
+        Ok(Replay::new(records))
+    }
+
+    fn input(&mut self, mut data: &[u8]) -> std::io::Result<()> {
+        // The handshake is echoed back, the same as a server using the same protocol version
+        if let Some(line) = &mut self.handshake {
+            match data.iter().position(|&b| b == b'\n') {
+                Some(i) => {
+                    line.extend_from_slice(&data[..=i]);
+                    self.output.extend(line.iter());
+                    self.handshake = None;
+                    data = &data[i + 1..];
+                }
+                None => {
+                    line.extend_from_slice(data);
+                    return Ok(());
+                }
+            }
+        }
+
+        self.requests.0.extend_from_slice(data);
+        while let Some((command, _)) = self.requests.next() {
+            let record = self.records.pop_front().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("No recorded response left for {}", command),
+                )
+            })?;
+            if record.command != command {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Expected {} request, got {}", record.command, command),
+                ));
+            }
+            self.output.extend(record.response);
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for Replay {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let n = buf.remaining().min(this.output.len());
+        let data: Vec<u8> = this.output.drain(..n).collect();
+        buf.put_slice(&data);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Replay {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(self.get_mut().input(buf).map(|()| buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<C: Type, H: Hash> Client<Replay, C, H> {
+    /// Create a client that replays the session recorded in a file by `Client::with_recorder`
+    pub async fn replay(path: impl AsRef<std::path::Path>) -> std::io::Result<ReplayClient<C, H>> {
+        Self::builder().build(Replay::open(path)?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::testutil::MockServer;
+    use crate::prelude::*;
+    use std::io::ErrorKind;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_replay() -> std::io::Result<()> {
+        // Server that always reports `main` as the current branch
+        let server = MockServer::new(|command, _| match command {
+            "get_current_branch" => MockServer::ok("main"),
+            _ => MockServer::ok(()),
+        })
+        .await?;
+        let addr = server.addr();
+
+        let path = std::env::temp_dir().join(format!("irmin-rs-replay-{}", std::process::id()));
+        let client = Client::<Tcp, Bytes, Blake2b>::new(addr)
+            .await?
+            .with_recorder(std::fs::File::create(&path)?);
+        client.ping().await?;
+        assert_eq!(client.get_current_branch().await?, "main");
+        client.close().await?;
+
+        let replay = Replay::open(&path)?;
+        std::fs::remove_file(&path)?;
+        let commands: Vec<&str> = replay.records.iter().map(|r| r.command.as_str()).collect();
+        assert_eq!(commands, ["ping", "get_current_branch"]);
+
+        let client = Client::<Replay, Bytes, Blake2b>::builder()
+            .build(replay)
+            .await?;
+        client.ping().await?;
+        assert_eq!(client.get_current_branch().await?, "main");
+        let e = client.ping().await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+
+use crate::client::{Client, Tcp};
+use crate::{Hash, Type};
+
+/// How long to wait for a newly started server to respond to a ping
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delay between attempts to connect while waiting for the server to start
+const STARTUP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Used to give each server started by this process its own repository
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// An `irmin-server` process running on an ephemeral port with a temporary repository, used by
+/// integration tests that need a real server instead of a mock. The process is killed and the
+/// repository removed when this is dropped, so it should outlive any clients connected to it.
+pub struct Server {
+    child: Child,
+    root: PathBuf,
+    addr: SocketAddr,
+}
+
+impl Server {
+    /// Find the `irmin-server` binary, using the path in the `IRMIN_SERVER` environment variable
+    /// if it's set, otherwise searching `PATH`
+    pub fn binary() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os("IRMIN_SERVER") {
+            return Some(PathBuf::from(path));
+        }
+        let paths = std::env::var_os("PATH")?;
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join("irmin-server"))
+            .find(|path| path.is_file())
+    }
+
+    /// Start a server and wait until it responds to a ping, returns an error with
+    /// `ErrorKind::NotFound` if the binary can't be found
+    pub async fn start() -> std::io::Result<Server> {
+        let (server, client) = Self::start_with_client::<crate::Bytes, crate::Blake2b>().await?;
+        client.close().await?;
+        Ok(server)
+    }
+
+    async fn start_with_client<C: Type, H: Hash>() -> std::io::Result<(Server, Client<Tcp, C, H>)> {
+        let binary = Self::binary().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "irmin-server not found, set IRMIN_SERVER or add it to PATH",
+            )
+        })?;
+
+        // The port is only reserved until the listener is dropped, if another process takes it
+        // first the server fails to start and the error is returned below
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let root = std::env::temp_dir().join(format!(
+            "irmin-rs-server-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&root)?;
+
+        let child = Command::new(&binary)
+            .arg("--uri")
+            .arg(format!("tcp://{}", addr))
+            .arg("--root")
+            .arg(&root)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn();
+        let mut server = match child {
+            Ok(child) => Server { child, root, addr },
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&root);
+                return Err(e);
+            }
+        };
+
+        let client = server.wait_until_ready().await?;
+        Ok((server, client))
+    }
+
+    /// Poll the server with pings until one succeeds, the server may not be listening yet when
+    /// the first attempts are made
+    async fn wait_until_ready<C: Type, H: Hash>(&mut self) -> std::io::Result<Client<Tcp, C, H>> {
+        let start = Instant::now();
+        loop {
+            if let Some(status) = self.child.try_wait()? {
+                return Err(std::io::Error::other(format!(
+                    "irmin-server exited during startup: {}",
+                    status
+                )));
+            }
+
+            let err = match Client::<Tcp, C, H>::new(self.addr).await {
+                Ok(client) => match client.ping().await {
+                    Ok(()) => return Ok(client),
+                    Err(e) => {
+                        client.discard();
+                        e
+                    }
+                },
+                Err(e) => e,
+            };
+
+            if start.elapsed() > STARTUP_TIMEOUT {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("irmin-server didn't start in time: {}", err),
+                ));
+            }
+            tokio::time::sleep(STARTUP_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Get the address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Open a new connection to the server
+    pub async fn client<C: Type, H: Hash>(&self) -> std::io::Result<Client<Tcp, C, H>> {
+        Client::<Tcp, C, H>::new(self.addr).await
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Start an `irmin-server` process and connect to it, see `Server`
+pub async fn spawn<C: Type, H: Hash>() -> std::io::Result<(Client<Tcp, C, H>, Server)> {
+    let (server, client) = Server::start_with_client().await?;
+    Ok((client, server))
+}
+
+/// Response returned by a `MockServer` handler: the status byte, where anything other than 0
+/// means the payload is an encoded error message, followed by the encoded payload
+pub type Response = (u8, Vec<u8>);
+
+type Handler = Arc<Mutex<dyn FnMut(&str, &[u8]) -> Response + Send>>;
+
+/// An in-process server that completes the handshake and answers each request by calling a
+/// handler with the command, without the trailing newline, and the encoded message. Used by
+/// tests that need specific responses instead of a real server. Every connection shares the
+/// same handler, and no more connections are accepted once this is dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Start a server on an ephemeral port that responds to requests using `handler`
+    pub async fn new<F>(handler: F) -> std::io::Result<MockServer>
+    where
+        F: FnMut(&str, &[u8]) -> Response + Send + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let handler: Handler = Arc::new(Mutex::new(handler));
+        let task = tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let _ = Self::serve(conn, handler).await;
+                });
+            }
+        });
+        Ok(MockServer { addr, task })
+    }
+
+    async fn serve(conn: tokio::net::TcpStream, handler: Handler) -> std::io::Result<()> {
+        let mut conn = BufReader::new(BufWriter::new(conn));
+        let mut line = String::new();
+        conn.read_line(&mut line).await?;
+        conn.write_all(line.as_bytes()).await?;
+        conn.flush().await?;
+        loop {
+            line.clear();
+            if conn.read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+            let mut data = vec![0u8; conn.read_i64().await? as usize];
+            conn.read_exact(&mut data).await?;
+            let (status, res) = (handler.lock().unwrap())(line.trim_end_matches('\n'), &data);
+            conn.write_u8(status).await?;
+            conn.write_i64(res.len() as i64).await?;
+            conn.write_all(&res).await?;
+
+            // Only flush once every request that has been received is answered, so pipelined
+            // requests are answered in batches
+            if conn.buffer().is_empty() {
+                conn.flush().await?;
+            }
+        }
+    }
+
+    /// A successful response containing `value`
+    pub fn ok<T: Type>(value: T) -> Response {
+        let mut data = Vec::new();
+        value
+            .encode_bin(&mut data)
+            .expect("Unable to encode response");
+        (0, data)
+    }
+
+    /// An error response with the message `msg`
+    pub fn error(msg: &str) -> Response {
+        let (_, data) = Self::ok(msg);
+        (1, data)
+    }
+
+    /// Get the address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Open a new connection to the server
+    pub async fn client<C: Type, H: Hash>(&self) -> std::io::Result<Client<Tcp, C, H>> {
+        Client::<Tcp, C, H>::new(self.addr).await
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
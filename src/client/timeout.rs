@@ -0,0 +1,184 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+#[cfg(feature = "replay")]
+use crate::client::replay::Recorder;
+
+/// Read and write timeouts shared between a `Connection` and its socket, stored in nanoseconds
+/// with `0` meaning no timeout
+#[derive(Default)]
+pub(crate) struct Timeouts {
+    read: AtomicU64,
+    write: AtomicU64,
+}
+
+fn to_nanos(timeout: Option<Duration>) -> std::io::Result<u64> {
+    match timeout {
+        None => Ok(0),
+        Some(t) if t.is_zero() => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Cannot set a zero duration timeout",
+        )),
+        Some(t) => Ok(t.as_nanos().min(u64::MAX as u128) as u64),
+    }
+}
+
+fn of_nanos(n: u64) -> Option<Duration> {
+    if n == 0 {
+        None
+    } else {
+        Some(Duration::from_nanos(n))
+    }
+}
+
+impl Timeouts {
+    pub(crate) fn read(&self) -> Option<Duration> {
+        of_nanos(self.read.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn write(&self) -> Option<Duration> {
+        of_nanos(self.write.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_read(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.read.store(to_nanos(timeout)?, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub(crate) fn set_write(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.write.store(to_nanos(timeout)?, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Socket wrapper that fails any single read or write that makes no progress for longer than
+/// the configured timeout. A timeout leaves the stream in an unknown state, so the connection is
+/// poisoned.
+pub(crate) struct Deadline<Socket> {
+    inner: Socket,
+    timeouts: Arc<Timeouts>,
+    poisoned: Arc<AtomicBool>,
+    read: Option<Pin<Box<Sleep>>>,
+    write: Option<Pin<Box<Sleep>>>,
+    #[cfg(feature = "replay")]
+    recorder: Arc<Recorder>,
+}
+
+impl<Socket> Deadline<Socket> {
+    pub(crate) fn new(inner: Socket, timeouts: Arc<Timeouts>, poisoned: Arc<AtomicBool>) -> Self {
+        Deadline {
+            inner,
+            timeouts,
+            poisoned,
+            read: None,
+            write: None,
+            #[cfg(feature = "replay")]
+            recorder: Arc::default(),
+        }
+    }
+
+    /// Get the recorder that is passed every byte read from and written to the socket
+    #[cfg(feature = "replay")]
+    pub(crate) fn recorder(&self) -> &Arc<Recorder> {
+        &self.recorder
+    }
+}
+
+/// Called after the socket returns `Pending`, returns an error once `timeout` has elapsed
+fn poll_deadline(
+    sleep: &mut Option<Pin<Box<Sleep>>>,
+    timeout: Option<Duration>,
+    poisoned: &AtomicBool,
+    cx: &mut Context<'_>,
+) -> Poll<std::io::Error> {
+    let timeout = match timeout {
+        Some(t) => t,
+        None => {
+            *sleep = None;
+            return Poll::Pending;
+        }
+    };
+
+    let s = sleep.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+    match s.as_mut().poll(cx) {
+        Poll::Ready(()) => {
+            *sleep = None;
+            poisoned.store(true, Ordering::Relaxed);
+            Poll::Ready(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Socket operation timed out",
+            ))
+        }
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+impl<Socket: Unpin + AsyncRead> AsyncRead for Deadline<Socket> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        #[cfg(feature = "replay")]
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(res) => {
+                this.read = None;
+                #[cfg(feature = "replay")]
+                this.recorder.received(&buf.filled()[filled..]);
+                Poll::Ready(res)
+            }
+            Poll::Pending => {
+                poll_deadline(&mut this.read, this.timeouts.read(), &this.poisoned, cx).map(Err)
+            }
+        }
+    }
+}
+
+impl<Socket: Unpin + AsyncWrite> AsyncWrite for Deadline<Socket> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(res) => {
+                this.write = None;
+                #[cfg(feature = "replay")]
+                if let Ok(n) = res {
+                    this.recorder.sent(&buf[..n]);
+                }
+                Poll::Ready(res)
+            }
+            Poll::Pending => {
+                poll_deadline(&mut this.write, this.timeouts.write(), &this.poisoned, cx).map(Err)
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_flush(cx) {
+            Poll::Ready(res) => {
+                this.write = None;
+                Poll::Ready(res)
+            }
+            Poll::Pending => {
+                poll_deadline(&mut this.write, this.timeouts.write(), &this.poisoned, cx).map(Err)
+            }
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
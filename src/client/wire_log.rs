@@ -0,0 +1,75 @@
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+
+/// Destination for the frames logged by `Client::with_wire_log`, when no writer has been set
+/// logging only costs a single atomic load per frame
+#[derive(Default)]
+pub(crate) struct WireLog(OnceLock<Mutex<Box<dyn Write + Send>>>);
+
+impl WireLog {
+    pub(crate) fn set(&self, w: Box<dyn Write + Send>) {
+        let mut w = Some(w);
+        let log = self.0.get_or_init(|| Mutex::new(w.take().unwrap()));
+        if let Some(w) = w {
+            *log.lock().unwrap() = w;
+        }
+    }
+
+    /// Log a line of text, like a command name or the handshake
+    pub(crate) fn line(&self, dir: char, line: impl FnOnce() -> String) {
+        if let Some(log) = self.0.get() {
+            let mut w = log.lock().unwrap();
+            let _ = writeln!(w, "{} {}", dir, line().trim_end());
+            let _ = w.flush();
+        }
+    }
+
+    /// Log a hex/ASCII dump of `data`
+    pub(crate) fn frame(&self, dir: char, data: &[u8]) {
+        if let Some(log) = self.0.get() {
+            let mut w = log.lock().unwrap();
+            let _ = dump(&mut *w, dir, data);
+            let _ = w.flush();
+        }
+    }
+}
+
+fn dump(w: &mut dyn Write, dir: char, data: &[u8]) -> std::io::Result<()> {
+    writeln!(w, "{} frame ({} bytes)", dir, data.len())?;
+    for (i, chunk) in data.chunks(16).enumerate() {
+        write!(w, "{} {:08x}:", dir, i * 16)?;
+        for b in chunk {
+            write!(w, " {:02x}", b)?;
+        }
+        for _ in chunk.len()..16 {
+            write!(w, "   ")?;
+        }
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(w, "  {}", ascii)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_dump() {
+        let mut out = Vec::new();
+        super::dump(&mut out, '>', b"\x00\x03abc").unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some("> frame (5 bytes)"));
+        let line = format!("> 00000000: 00 03 61 62 63{}  ..abc", " ".repeat(33));
+        assert_eq!(lines.next(), Some(line.as_str()));
+        assert_eq!(lines.next(), None);
+    }
+}
@@ -0,0 +1,123 @@
+/// Errors specific to irmin-rs, these are carried inside a `std::io::Error` so they can be returned
+/// from any function that returns a `std::io::Result`, use `Error::of_io` to get them back
+#[derive(Debug)]
+pub enum Error {
+    /// The server closed the connection before a complete response was received
+    ConnectionClosed,
+
+    /// A value could not be decoded, `offset` is the position in the input where decoding failed
+    Decode {
+        type_name: &'static str,
+        offset: usize,
+        source: std::io::Error,
+    },
+
+    /// A compare-and-set kept failing because the value was changed concurrently
+    Conflict { attempts: usize },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::ConnectionClosed => write!(f, "Connection closed by server"),
+            Error::Decode {
+                type_name,
+                offset,
+                source,
+            } => write!(
+                f,
+                "Unable to decode {} at offset {}: {}",
+                type_name, offset, source
+            ),
+            Error::Conflict { attempts } => {
+                write!(
+                    f,
+                    "Value changed concurrently, gave up after {} attempts",
+                    attempts
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Decode { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> std::io::Error {
+        let kind = match e {
+            Error::ConnectionClosed => std::io::ErrorKind::ConnectionAborted,
+            Error::Decode { .. } => std::io::ErrorKind::InvalidData,
+            Error::Conflict { .. } => std::io::ErrorKind::Other,
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
+impl Error {
+    /// Get the `Error` carried by an IO error, if there is one
+    pub fn of_io(e: &std::io::Error) -> Option<&Error> {
+        e.get_ref().and_then(|e| e.downcast_ref())
+    }
+
+    /// Check if the operation that failed might succeed if it is tried again.
+    /// `ConnectionClosed` is retryable using a new connection and `Conflict` is retryable once
+    /// the value has been read again. `Decode` errors are not retryable, since the same data
+    /// would fail to decode again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::ConnectionClosed | Error::Conflict { .. } => true,
+            Error::Decode { .. } => false,
+        }
+    }
+
+    /// Like `is_retryable`, for any IO error. Errors that don't carry an `Error` are retryable
+    /// if they are caused by the connection timing out or being interrupted or lost, errors
+    /// reported by the server are not.
+    pub fn is_retryable_io(e: &std::io::Error) -> bool {
+        use std::io::ErrorKind::*;
+
+        match Error::of_io(e) {
+            Some(e) => e.is_retryable(),
+            None => matches!(
+                e.kind(),
+                TimedOut
+                    | Interrupted
+                    | WouldBlock
+                    | NotConnected
+                    | ConnectionReset
+                    | ConnectionAborted
+                    | BrokenPipe
+                    | UnexpectedEof
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn test_retryable() {
+        assert!(Error::is_retryable_io(&Error::ConnectionClosed.into()));
+        assert!(Error::is_retryable_io(
+            &Error::Conflict { attempts: 1 }.into()
+        ));
+        let decode = Error::Decode {
+            type_name: "test",
+            offset: 0,
+            source: ErrorKind::InvalidData.into(),
+        };
+        assert!(!Error::is_retryable_io(&decode.into()));
+        assert!(Error::is_retryable_io(&ErrorKind::TimedOut.into()));
+        assert!(!Error::is_retryable_io(&ErrorKind::InvalidInput.into()));
+    }
+}
@@ -31,11 +31,32 @@ hash_type!(Blake2b);
 hash_type!(Sha1);
 
 pub trait Hash: Type + Clone + Sized + PartialEq {
+    /// Number of bytes in a digest
     fn size() -> usize;
 
+    /// Name of the hash algorithm
     fn name() -> &'static str;
 
     fn hash(x: impl AsRef<[u8]>) -> Self;
+
+    /// Wrap a raw digest, returns an error if it isn't `size` bytes long
+    fn from_bytes(b: &[u8]) -> std::io::Result<Self> {
+        if b.len() != Self::size() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid {} digest: expected {} bytes, got {}",
+                    Self::name(),
+                    Self::size(),
+                    b.len()
+                ),
+            ));
+        }
+        Self::decode_bin(&mut &b[..])
+    }
+
+    /// Get the raw digest
+    fn as_bytes(&self) -> &[u8];
 }
 
 impl Hash for Blake2b {
@@ -47,6 +68,10 @@ impl Hash for Blake2b {
         "blake2b"
     }
 
+    fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
     fn hash(s: impl AsRef<[u8]>) -> Self {
         let digest = blake2::Blake2b::digest(s.as_ref());
         Blake2b(digest.as_slice().to_vec())
@@ -62,6 +87,10 @@ impl Hash for Sha1 {
         "sha1"
     }
 
+    fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
     fn hash(s: impl AsRef<[u8]>) -> Self {
         let mut hash = sha1::Sha1::default();
         hash.update(s.as_ref());
@@ -69,3 +98,24 @@ impl Hash for Sha1 {
         Sha1(digest.bytes().to_vec())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_introspection() {
+        assert_eq!(Blake2b::size(), Blake2b::hash("abc").as_ref().len());
+        assert_eq!(Sha1::size(), Sha1::hash("abc").as_ref().len());
+        assert_eq!(Blake2b::name(), "blake2b");
+        assert_eq!(Sha1::name(), "sha1");
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let hash = Blake2b::hash("abc");
+        assert_eq!(Blake2b::from_bytes(hash.as_bytes()).unwrap(), hash);
+        let e = Sha1::from_bytes(hash.as_bytes()).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
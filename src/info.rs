@@ -1,10 +1,15 @@
 use crate::{irmin, Type};
 
-#[derive(Debug, Clone, PartialEq, PartialOrd, Type)]
+use std::borrow::Cow;
+
+/// Commit metadata, infos are compared field by field and ordered by date first. The author and
+/// message are kept as bytes since Irmin doesn't require them to be valid UTF-8.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Type)]
 pub struct Info {
+    /// Seconds since the Unix epoch, the same unit Irmin uses
     pub date: i64,
-    pub author: String,
-    pub message: String,
+    author: Vec<u8>,
+    message: Vec<u8>,
 }
 
 impl Default for Info {
@@ -23,18 +28,86 @@ impl Info {
 
         Info {
             date: date as i64,
-            author: String::from("irmin-rs"),
-            message: String::new(),
+            author: b"irmin-rs".to_vec(),
+            message: Vec::new(),
+        }
+    }
+
+    /// Create a new `Info` with the author taken from the environment, the first of
+    /// `IRMIN_AUTHOR`, `GIT_AUTHOR_NAME`, `USER` or `USERNAME` that is set is used, otherwise it
+    /// falls back to the same author as `Info::new`
+    pub fn from_env() -> Info {
+        let info = Info::new();
+        let vars = ["IRMIN_AUTHOR", "GIT_AUTHOR_NAME", "USER", "USERNAME"];
+        match vars
+            .iter()
+            .filter_map(|v| std::env::var(v).ok())
+            .find(|v| !v.is_empty())
+        {
+            Some(author) => info.with_author(author),
+            None => info,
         }
     }
 
-    pub fn with_message(mut self, message: impl Into<String>) -> Self {
+    /// Create a new `Info` with `message` as the commit message
+    pub fn commit(message: impl std::fmt::Display) -> Info {
+        Info::new().with_message(message.to_string())
+    }
+
+    /// Create a new `Info` for merging the branch `from` into `into`, using the same message as
+    /// `git merge`
+    pub fn merge(from: impl AsRef<str>, into: impl AsRef<str>) -> Info {
+        Info::new().with_message(format!(
+            "Merge branch '{}' into {}",
+            from.as_ref(),
+            into.as_ref()
+        ))
+    }
+
+    pub fn with_message(mut self, message: impl Into<Vec<u8>>) -> Self {
         self.message = message.into();
         self
     }
 
-    pub fn with_author(mut self, author: impl Into<String>) -> Self {
+    pub fn with_author(mut self, author: impl Into<Vec<u8>>) -> Self {
         self.author = author.into();
         self
     }
+
+    /// Get the author, invalid UTF-8 is replaced with `U+FFFD`
+    pub fn author(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.author)
+    }
+
+    /// Get the commit message, invalid UTF-8 is replaced with `U+FFFD`
+    pub fn message(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.message)
+    }
+
+    /// Get the author without any conversion
+    pub fn author_bytes(&self) -> &[u8] {
+        &self.author
+    }
+
+    /// Get the commit message without any conversion
+    pub fn message_bytes(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// Get the date in seconds since the Unix epoch
+    pub fn date_seconds(&self) -> i64 {
+        self.date
+    }
+
+    /// Get the date in nanoseconds since the Unix epoch, returns `None` if it doesn't fit in an
+    /// `i64`
+    pub fn date_nanos(&self) -> Option<i64> {
+        self.date.checked_mul(1_000_000_000)
+    }
+
+    /// Convert `date` to a UTC timestamp, returns `None` if it is out of range
+    #[cfg(feature = "chrono")]
+    pub fn date_time(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        chrono::DateTime::from_timestamp(self.date, 0)
+    }
 }
@@ -0,0 +1,43 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Bytes, Type};
+
+/// Wrapper used to store any `serde` serializable value as contents, the value is encoded as a
+/// JSON string.
+///
+/// Since the contents are JSON strings, they will not have the same hash as the binary encoding
+/// used by an irmin store with a matching OCaml contents type, so `Json` should only be used with
+/// stores that contain string contents.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Json<T>(pub T);
+
+impl<T> Json<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Json<T> {
+    fn from(x: T) -> Self {
+        Json(x)
+    }
+}
+
+impl<T> AsRef<T> for Json<T> {
+    fn as_ref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Type for Json<T> {
+    fn encode_bin<W: std::io::Write>(&self, dest: &mut W) -> std::io::Result<usize> {
+        let data = serde_json::to_vec(&self.0)?;
+        Bytes::from(data).encode_bin(dest)
+    }
+
+    fn decode_bin<R: std::io::Read>(src: &mut R) -> std::io::Result<Self> {
+        let data = Bytes::decode_bin(src)?;
+        let x = serde_json::from_slice(data.as_ref())?;
+        Ok(Json(x))
+    }
+}
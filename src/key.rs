@@ -1,20 +1,62 @@
+use std::cell::Cell;
+
 use crate::Type;
+#[cfg(feature = "ocaml")]
 use ocaml_interop::*;
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Key(Vec<String>);
 
+/// Limits applied when decoding a `Key`, these protect against untrusted input allocating
+/// unbounded amounts of memory. Clients use the limits set with `ClientBuilder::key_limits`,
+/// other decoding uses the defaults unless it's run inside `Key::with_decode_limits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyLimits {
+    pub max_steps: usize,
+    pub max_step_len: usize,
+}
+
+impl Default for KeyLimits {
+    fn default() -> KeyLimits {
+        KeyLimits {
+            max_steps: DEFAULT_MAX_STEPS,
+            max_step_len: DEFAULT_MAX_STEP_LEN,
+        }
+    }
+}
+
+const DEFAULT_MAX_STEPS: usize = 1 << 16;
+const DEFAULT_MAX_STEP_LEN: usize = 1 << 20;
+
+thread_local! {
+    static LIMITS: Cell<KeyLimits> = Cell::new(KeyLimits::default());
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
 impl Type for Key {
     fn encode_bin<W: std::io::Write>(&self, dest: &mut W) -> std::io::Result<usize> {
         self.0.encode_bin(dest)
     }
 
+    /// Failures, including exceeding the decode limits, are reported as `Error::Decode` with
+    /// the offset from the start of the key
     fn decode_bin<R: std::io::Read>(src: &mut R) -> std::io::Result<Self> {
-        let x = Vec::<String>::decode_bin(src)?;
-        Ok(Key(x))
+        let mut src = crate::r#type::Offset::new(src);
+        Key::decode_steps(&mut src).map_err(|source| {
+            crate::Error::Decode {
+                type_name: "Key",
+                offset: src.offset(),
+                source,
+            }
+            .into()
+        })
     }
 }
 
+#[cfg(feature = "ocaml")]
 unsafe impl FromOCaml<String> for Key {
     fn from_ocaml(v: OCaml<'_, String>) -> Self {
         let mut bytes = v.as_bytes();
@@ -22,6 +64,7 @@ unsafe impl FromOCaml<String> for Key {
     }
 }
 
+#[cfg(feature = "ocaml")]
 unsafe impl ToOCaml<String> for Key {
     fn to_ocaml<'a>(&self, rt: &'a mut OCamlRuntime) -> OCaml<'a, String> {
         let mut data = Vec::new();
@@ -31,7 +74,81 @@ unsafe impl ToOCaml<String> for Key {
     }
 }
 
+impl<S: Into<String>> std::iter::FromIterator<S> for Key {
+    fn from_iter<I: IntoIterator<Item = S>>(iter: I) -> Key {
+        let mut key = Key::empty();
+        for step in iter {
+            key.push(step);
+        }
+        key
+    }
+}
+
+impl From<Vec<String>> for Key {
+    fn from(x: Vec<String>) -> Key {
+        x.into_iter().collect()
+    }
+}
+
+impl<'a> From<&[&'a str]> for Key {
+    fn from(x: &[&'a str]) -> Key {
+        Key::new(x)
+    }
+}
+
+impl<'a, const N: usize> From<[&'a str; N]> for Key {
+    fn from(x: [&'a str; N]) -> Key {
+        Key::new(x)
+    }
+}
+
 impl Key {
+    /// Get the limits currently used when decoding keys on this thread
+    pub fn decode_limits() -> KeyLimits {
+        LIMITS.with(|l| l.get())
+    }
+
+    /// Run `f` with `limits` used when decoding keys, the previous limits are restored
+    /// afterwards. Decoding is synchronous, so this only affects keys decoded by `f` itself.
+    pub fn with_decode_limits<R>(limits: KeyLimits, f: impl FnOnce() -> R) -> R {
+        struct Restore(KeyLimits);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                LIMITS.with(|l| l.set(self.0));
+            }
+        }
+
+        let _restore = Restore(LIMITS.with(|l| l.replace(limits)));
+        f()
+    }
+
+    fn decode_steps<R: std::io::Read>(src: &mut R) -> std::io::Result<Key> {
+        let limits = Key::decode_limits();
+        let n = usize::decode_bin(src)?;
+        if n > limits.max_steps {
+            return Err(invalid("Key has too many steps"));
+        }
+
+        let mut steps = Vec::new();
+        for _ in 0..n {
+            let len = usize::decode_bin(src)?;
+            if len > limits.max_step_len {
+                return Err(invalid("Key step is too long"));
+            }
+            let mut step = vec![0u8; len];
+            src.read_exact(&mut step)?;
+            match String::from_utf8(step) {
+                // Empty steps are dropped so decoded keys are in canonical form, see
+                // `Key::normalized`
+                Ok(step) if step.is_empty() => (),
+                Ok(step) => steps.push(step),
+                Err(_) => return Err(invalid("Invalid string")),
+            }
+        }
+        Ok(Key(steps))
+    }
+
     pub fn new<'a>(a: impl AsRef<[&'a str]>) -> Key {
         Key(a
             .as_ref()
@@ -61,6 +178,23 @@ impl Key {
         self.0.pop()
     }
 
+    /// Get the canonical form of this key, where no step is empty. Steps are otherwise kept
+    /// exactly as they are: they aren't trimmed or Unicode normalized, and a step containing `/`
+    /// is still a single step. Two keys refer to the same node on the server if and only if
+    /// their canonical forms are equal.
+    ///
+    /// Every way of creating a `Key`, including `decode_bin`, already drops empty steps, so
+    /// keys are always canonical and comparing, hashing or encoding them never needs to
+    /// normalize first. This is the identity on any `Key` value, it's provided to state that
+    /// guarantee explicitly.
+    pub fn normalized(&self) -> Key {
+        self.0.iter().filter(|step| !step.is_empty()).collect()
+    }
+
+    pub fn steps(&self) -> &[String] {
+        &self.0
+    }
+
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
@@ -69,11 +203,176 @@ impl Key {
         self.0.len()
     }
 
+    /// Check if the steps of `prefix` are the first steps of this key
+    pub fn starts_with(&self, prefix: &Key) -> bool {
+        self.0.starts_with(&prefix.0)
+    }
+
+    /// Get the remaining steps after `prefix`, returns `None` if the key doesn't start with `prefix`
+    pub fn strip_prefix(&self, prefix: &Key) -> Option<Key> {
+        self.0
+            .strip_prefix(prefix.0.as_slice())
+            .map(|x| Key(x.to_vec()))
+    }
+
+    /// Create a key from steps given as bytes, unlike `from_string` steps may contain `/`. Steps
+    /// are stored as strings, so this is lossy: invalid UTF-8 sequences are replaced with
+    /// `U+FFFD`
+    pub fn from_steps_bytes(steps: &[&[u8]]) -> Key {
+        let mut key = Key::empty();
+        for step in steps {
+            key.push(String::from_utf8_lossy(step));
+        }
+        key
+    }
+
+    /// Join the steps using `/`, this is lossy: steps that contain `/` can't be recovered using
+    /// `from_string`
     pub fn to_string(&self) -> String {
         self.0.join("/")
     }
 
+    /// Split a `/`-separated path into steps, empty steps are dropped. Use `Key::new` or
+    /// `Key::from_steps_bytes` for steps that contain `/`
     pub fn from_string(s: impl AsRef<str>) -> Key {
         Key::new(s.as_ref().split("/").collect::<Vec<_>>())
     }
 }
+
+/// Keys are serialized as `/`-separated paths, the same as `Key::to_string`. Keys with steps
+/// that contain `/` or are empty can't be represented this way and fail to serialize.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Key {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if let Some(step) = self.0.iter().find(|s| s.is_empty() || s.contains('/')) {
+            return Err(serde::ser::Error::custom(format!(
+                "Key step can't be represented as a path: {:?}",
+                step
+            )));
+        }
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Key {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Key, D::Error> {
+        let s = std::borrow::Cow::<str>::deserialize(deserializer)?;
+        Ok(Key::from_string(s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Key, KeyLimits, Type};
+
+    #[test]
+    fn test_prefix() {
+        let key = Key::new(["a", "b", "c"]);
+        assert!(key.starts_with(&Key::new(["a", "b"])));
+        assert!(key.starts_with(&Key::empty()));
+        assert!(!key.starts_with(&Key::new(["a", "bc"])));
+        assert_eq!(
+            key.strip_prefix(&Key::new(["a"])),
+            Some(Key::new(["b", "c"]))
+        );
+        assert_eq!(key.strip_prefix(&key), Some(Key::empty()));
+        assert_eq!(key.strip_prefix(&Key::new(["b"])), None);
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        let mut data = Vec::new();
+        Key::new(["a", "b"]).encode_bin(&mut data).unwrap();
+        assert!(Key::decode_bin(&mut data.as_slice()).is_ok());
+
+        let mut data = Vec::new();
+        (super::DEFAULT_MAX_STEPS + 1)
+            .encode_bin(&mut data)
+            .unwrap();
+        assert!(Key::decode_bin(&mut data.as_slice()).is_err());
+
+        let mut data = Vec::new();
+        (1usize, super::DEFAULT_MAX_STEP_LEN + 1)
+            .encode_bin(&mut data)
+            .unwrap();
+        let e = Key::decode_bin(&mut data.as_slice()).unwrap_err();
+        match crate::Error::of_io(&e) {
+            Some(crate::Error::Decode {
+                type_name, offset, ..
+            }) => {
+                assert_eq!(*type_name, "Key");
+                assert_eq!(*offset, 4);
+            }
+            _ => panic!("Expected a decode error"),
+        }
+
+        // Limits only apply inside `with_decode_limits`
+        let limits = KeyLimits {
+            max_steps: 1,
+            ..KeyLimits::default()
+        };
+        let mut data = Vec::new();
+        Key::new(["a", "b"]).encode_bin(&mut data).unwrap();
+        assert!(Key::with_decode_limits(limits, || Key::decode_bin(&mut data.as_slice())).is_err());
+        assert!(Key::decode_bin(&mut data.as_slice()).is_ok());
+        assert_eq!(Key::decode_limits(), KeyLimits::default());
+    }
+
+    #[test]
+    fn test_from() {
+        let key = Key::new(["a", "b", "c"]);
+        assert_eq!(Key::from(["a", "b", "c"]), key);
+        assert_eq!(Key::from(&["a", "", "b", "c"][..]), key);
+        assert_eq!(vec!["a", "b", "c"].into_iter().collect::<Key>(), key);
+        let steps = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        assert_eq!(Key::from(steps), key);
+    }
+
+    #[test]
+    fn test_normalized() {
+        let key = Key::new(["a", "b/c"]);
+        assert_eq!(key.normalized(), key);
+        assert_eq!(Key::new(["", "a", "", "b/c"]), key);
+        assert_eq!(
+            Key::from_string("/a//b/").normalized(),
+            Key::new(["a", "b"])
+        );
+
+        // Keys encoded with empty steps are decoded without them
+        let mut data = Vec::new();
+        vec!["", "a", "", "b/c", ""].encode_bin(&mut data).unwrap();
+        let decoded = Key::decode_bin(&mut data.as_slice()).unwrap();
+        assert_eq!(decoded, key);
+        assert_eq!(decoded.len(), 2);
+    }
+
+    #[test]
+    fn test_steps_bytes() {
+        let key = Key::from_steps_bytes(&[b"a/b", b"c"]);
+        assert_eq!(key.len(), 2);
+        assert_eq!(key.steps()[0], "a/b");
+        assert_ne!(Key::from_string(key.to_string()), key);
+
+        let mut data = Vec::new();
+        key.encode_bin(&mut data).unwrap();
+        assert_eq!(Key::decode_bin(&mut data.as_slice()).unwrap(), key);
+
+        let key = Key::from_steps_bytes(&[b"a\xff", b"", b"\xff"]);
+        assert_eq!(key, Key::new(["a\u{fffd}", "\u{fffd}"]));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let key = Key::new(["a", "b", "c"]);
+        let s = serde_json::to_string(&key).unwrap();
+        assert_eq!(s, r#""a/b/c""#);
+        let x: Key = serde_json::from_str(&s).unwrap();
+        assert_eq!(x, key);
+
+        let x: Key = serde_json::from_str(r#""/a//b/""#).unwrap();
+        assert_eq!(x, Key::new(["a", "b"]));
+        assert!(serde_json::to_string(&Key::new(["a/b"])).is_err());
+    }
+}
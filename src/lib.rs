@@ -1,25 +1,38 @@
 #![recursion_limit = "128"]
 
-#[cfg(feature = "bindings")]
+#[cfg(feature = "ocaml")]
 pub mod bindings;
 
 #[cfg(feature = "client")]
 pub mod client;
 
+pub mod prelude;
+
 mod commit;
 pub use commit::*;
 
+mod error;
+pub use error::Error;
+
 mod hash;
 pub use hash::*;
 
 mod info;
 pub use info::Info;
 
+#[cfg(feature = "serde")]
+mod json;
+#[cfg(feature = "serde")]
+pub use json::Json;
+
 mod key;
-pub use key::Key;
+pub use key::{Key, KeyLimits};
+
+mod merge;
+pub use merge::Merge;
 
 mod tree;
-pub use tree::{Concrete, Tree};
+pub use tree::{Concrete, Diff, Kind, Tree, TreeBuilder};
 
 mod r#type;
 pub use r#type::*;
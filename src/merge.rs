@@ -0,0 +1,84 @@
+/// Three-way merge for contents, used by `Tree::merge` and `Concrete::merge` when both sides of a
+/// merge changed the same contents. The default implementation only succeeds when one side is
+/// unchanged or both sides made the same change, like Irmin's `Merge.idempotent`.
+pub trait Merge: Sized + Clone + PartialEq {
+    fn merge(old: Option<&Self>, left: &Self, right: &Self) -> Result<Self, String> {
+        if left == right || old == Some(right) {
+            Ok(left.clone())
+        } else if old == Some(left) {
+            Ok(right.clone())
+        } else {
+            Err(String::from("Conflicting contents"))
+        }
+    }
+}
+
+impl Merge for String {}
+
+impl Merge for Vec<u8> {}
+
+impl<'a> Merge for crate::Bytes<'a> {}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Blake2b, Bytes, Concrete, Key, Merge, Tree, TreeBuilder};
+
+    fn tree(entries: &[(&[&str], &str)]) -> Concrete<String> {
+        let mut builder = TreeBuilder::new();
+        for (key, value) in entries {
+            builder = builder.add(Key::new(key), value.to_string());
+        }
+        match builder.build::<Blake2b>().unwrap() {
+            Tree::Concrete(c) => c,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_merge_contents() {
+        let a = String::from("a");
+        let b = String::from("b");
+        assert_eq!(String::merge(Some(&a), &a, &b), Ok(b.clone()));
+        assert_eq!(String::merge(Some(&a), &b, &a), Ok(b.clone()));
+        assert_eq!(String::merge(None, &b, &b), Ok(b.clone()));
+        assert!(String::merge(None, &a, &b).is_err());
+    }
+
+    #[test]
+    fn test_merge_tree() {
+        let old = tree(&[(&["a"], "1"), (&["b", "c"], "2")]);
+        let left = tree(&[(&["a"], "3"), (&["b", "c"], "2")]);
+        let right = tree(&[(&["a"], "1"), (&["b", "c"], "2"), (&["b", "d"], "4")]);
+        let merged = Concrete::merge(Some(&old), &left, &right).unwrap();
+        assert_eq!(
+            merged,
+            tree(&[(&["a"], "3"), (&["b", "c"], "2"), (&["b", "d"], "4")])
+        );
+
+        let right = tree(&[(&["a"], "5"), (&["b", "c"], "2")]);
+        let e = Concrete::merge(Some(&old), &left, &right).unwrap_err();
+        assert!(e.contains("a"));
+
+        let right = tree(&[(&["b", "c"], "2")]);
+        assert!(Concrete::merge(Some(&old), &left, &right).is_err());
+    }
+
+    #[test]
+    fn test_merge_bytes() {
+        let tree = |entries: &[(&str, &'static [u8])]| {
+            let mut builder = TreeBuilder::new();
+            for (key, value) in entries {
+                builder = builder.add(Key::new([*key]), Bytes::from(*value));
+            }
+            builder.build::<Blake2b>().unwrap()
+        };
+        let old = tree(&[("a", b"1"), ("b", b"2")]);
+        let left = tree(&[("a", b"3"), ("b", b"2")]);
+        let right = tree(&[("a", b"1"), ("b", b"4")]);
+        let merged = Tree::merge(Some(&old), &left, &right).unwrap();
+        assert_eq!(merged, tree(&[("a", b"3"), ("b", b"4")]));
+
+        let right = tree(&[("a", b"5"), ("b", b"2")]);
+        assert!(Tree::merge(Some(&old), &left, &right).is_err());
+    }
+}
@@ -0,0 +1,12 @@
+//! Commonly used types and traits, import them all at once using `use irmin::prelude::*`
+
+pub use crate::{
+    Blake2b, Bytes, Commit, Concrete, Diff, Hash, Info, Key, Kind, Merge, Sha1, Str, Tree,
+    TreeBuilder, Type,
+};
+
+#[cfg(feature = "client")]
+pub use crate::client::{
+    Client, ClientBuilder, Database, ListStream, NodeStat, Pool, ServerInfo, Stats, Store, Tcp,
+    Unix,
+};
@@ -1,7 +1,7 @@
 use std::collections::BTreeMap;
 
 use crate as irmin;
-use crate::{Hash, Type};
+use crate::{Hash, Key, Merge, Type};
 
 #[derive(Debug, Clone, Type, PartialEq)]
 pub enum Tree<T: Type, H: Hash> {
@@ -10,6 +10,22 @@ pub enum Tree<T: Type, H: Hash> {
     Concrete(Concrete<T>),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Type)]
+pub enum Kind {
+    Contents,
+    Tree,
+}
+
+/// A change to the contents at a key, see `Concrete::diff`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Diff<T> {
+    Added(T),
+    Removed(T),
+
+    /// The contents changed from the first value to the second
+    Updated(T, T),
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Concrete<T> {
     Tree(BTreeMap<String, Concrete<T>>),
@@ -20,6 +36,145 @@ impl<T: Type, H: Hash> Tree<T, H> {
     pub fn empty() -> Self {
         Tree::Concrete(Concrete::empty())
     }
+
+    /// Get an `Encoder` that produces the same bytes as `encode_bin` a few nodes at a time
+    pub(crate) fn encoder(&self) -> Encoder<'_, T, H> {
+        Encoder {
+            tree: Some(self),
+            stack: Vec::new(),
+        }
+    }
+}
+
+impl<T: Type + Merge, H: Hash> Tree<T, H> {
+    /// Three-way merge of trees without any requests to the server, `old` is the common ancestor
+    /// of `left` and `right`. Only concrete trees can be merged locally, contents changed on both
+    /// sides are merged using `Merge::merge`.
+    pub fn merge(old: Option<&Self>, left: &Self, right: &Self) -> Result<Self, String> {
+        let concrete = |t: &Self| match t {
+            Tree::Concrete(c) => Ok(c.clone()),
+            _ => Err(String::from("Only concrete trees can be merged locally")),
+        };
+        let old = old.map(concrete).transpose()?;
+        let merged = Concrete::merge(old.as_ref(), &concrete(left)?, &concrete(right)?)?;
+        Ok(Tree::Concrete(merged))
+    }
+}
+
+/// Builds a tree locally from a list of keys and values, without any requests to the server,
+/// use `build` to get a `Tree` that can be passed to `Store::set_tree`
+#[derive(Debug, Clone)]
+pub struct TreeBuilder<T> {
+    entries: Vec<(Key, T)>,
+}
+
+impl<T> Default for TreeBuilder<T> {
+    fn default() -> Self {
+        TreeBuilder {
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<T: Type> TreeBuilder<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a value to the tree
+    pub fn add(mut self, key: Key, value: T) -> Self {
+        self.entries.push((key, value));
+        self
+    }
+
+    /// Build the tree, returns an error if a key is empty, set twice, or a key is used as both
+    /// contents and a node (for example `a` and `a/b`)
+    pub fn build<H: Hash>(self) -> std::io::Result<Tree<T, H>> {
+        let mut root = Concrete::empty();
+        for (key, value) in self.entries {
+            let conflict = || {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Conflicting key in tree: {}", key.to_string()),
+                )
+            };
+
+            let (last, parents) = match key.steps().split_last() {
+                Some(x) => x,
+                None => return Err(conflict()),
+            };
+
+            let mut node = &mut root;
+            for step in parents {
+                node = match node {
+                    Concrete::Tree(t) => t.entry(step.clone()).or_insert_with(Concrete::empty),
+                    Concrete::Contents(_) => return Err(conflict()),
+                }
+            }
+
+            match node {
+                Concrete::Tree(t) if !t.contains_key(last) => {
+                    t.insert(last.clone(), Concrete::Contents(value));
+                }
+                _ => return Err(conflict()),
+            }
+        }
+        Ok(Tree::Concrete(root))
+    }
+}
+
+enum Item<'a, T> {
+    Step(&'a String),
+    Node(&'a Concrete<T>),
+}
+
+/// Incremental version of `Tree::encode_bin`, used to write large trees without holding the
+/// complete encoding in memory
+pub(crate) struct Encoder<'a, T: Type, H: Hash> {
+    tree: Option<&'a Tree<T, H>>,
+    stack: Vec<Item<'a, T>>,
+}
+
+impl<'a, T: Type, H: Hash> Encoder<'a, T, H> {
+    /// Append encoded nodes to `buf` until it holds at least `limit` bytes, returns `false` once
+    /// the whole tree has been encoded
+    pub(crate) fn fill(&mut self, buf: &mut Vec<u8>, limit: usize) -> std::io::Result<bool> {
+        if let Some(tree) = self.tree.take() {
+            match tree {
+                Tree::Concrete(c) => {
+                    // `Concrete` is the third variant of `Tree`
+                    2isize.encode_bin(buf)?;
+                    self.stack.push(Item::Node(c));
+                }
+                t => {
+                    t.encode_bin(buf)?;
+                }
+            }
+        }
+
+        while buf.len() < limit {
+            match self.stack.pop() {
+                None => return Ok(false),
+                Some(Item::Step(step)) => {
+                    step.encode_bin(buf)?;
+                }
+                Some(Item::Node(Concrete::Contents(v))) => {
+                    1usize.encode_bin(buf)?;
+                    v.encode_bin(buf)?;
+                }
+                Some(Item::Node(Concrete::Tree(t))) => {
+                    0usize.encode_bin(buf)?;
+                    t.len().encode_bin(buf)?;
+                    for (k, v) in t.iter().rev() {
+                        self.stack.push(Item::Node(v));
+                        self.stack.push(Item::Step(k));
+                    }
+                }
+            }
+        }
+
+        Ok(!self.stack.is_empty())
+    }
 }
 
 impl<T: Type> Type for Concrete<T> {
@@ -51,6 +206,137 @@ impl<T: Type> Type for Concrete<T> {
     }
 }
 
+impl<T: Type + Merge> Concrete<T> {
+    /// Three-way merge of concrete trees, see `Tree::merge`
+    pub fn merge(old: Option<&Self>, left: &Self, right: &Self) -> Result<Self, String> {
+        let mut key = Key::empty();
+        match Self::merge_at(&mut key, old, Some(left), Some(right))? {
+            Some(merged) => Ok(merged),
+            None => Ok(Concrete::empty()),
+        }
+    }
+
+    fn merge_at(
+        key: &mut Key,
+        old: Option<&Self>,
+        left: Option<&Self>,
+        right: Option<&Self>,
+    ) -> Result<Option<Self>, String> {
+        if left == right || old == right {
+            return Ok(left.cloned());
+        } else if old == left {
+            return Ok(right.cloned());
+        }
+
+        let conflict =
+            |reason: String| format!("Merge conflict at {}: {}", key.to_string(), reason);
+        match (old, left, right) {
+            (old, Some(Concrete::Contents(l)), Some(Concrete::Contents(r))) => {
+                let old = match old {
+                    Some(Concrete::Contents(o)) => Some(o),
+                    _ => None,
+                };
+                T::merge(old, l, r)
+                    .map(|x| Some(Concrete::Contents(x)))
+                    .map_err(conflict)
+            }
+            (old, Some(Concrete::Tree(l)), Some(Concrete::Tree(r))) => {
+                let old = match old {
+                    Some(Concrete::Tree(o)) => Some(o),
+                    _ => None,
+                };
+                let mut merged = BTreeMap::new();
+                for step in l.keys().chain(r.keys()) {
+                    if merged.contains_key(step) {
+                        continue;
+                    }
+                    key.push(step.as_str());
+                    let node = Self::merge_at(
+                        key,
+                        old.and_then(|o| o.get(step)),
+                        l.get(step),
+                        r.get(step),
+                    );
+                    key.pop();
+                    if let Some(node) = node? {
+                        merged.insert(step.clone(), node);
+                    }
+                }
+                Ok(Some(Concrete::Tree(merged)))
+            }
+            (_, Some(_), Some(_)) => {
+                Err(conflict(String::from("Contents and tree at the same key")))
+            }
+            _ => Err(conflict(String::from("Removed and modified"))),
+        }
+    }
+}
+
+impl<T: Type + PartialEq> Concrete<T> {
+    /// Get the changes to contents needed to turn `old` into `new`, sorted by key. Trees only
+    /// appear through the contents they hold, replacing contents with a tree (or the other way
+    /// around) removes the old contents and adds the new ones.
+    pub fn diff(old: Self, new: Self) -> Vec<(Key, Diff<T>)> {
+        let mut diff = Vec::new();
+        Self::diff_at(&mut Key::empty(), Some(old), Some(new), &mut diff);
+        diff.sort_by(|a, b| a.0.cmp(&b.0));
+        diff
+    }
+
+    fn diff_at(
+        key: &mut Key,
+        old: Option<Self>,
+        new: Option<Self>,
+        diff: &mut Vec<(Key, Diff<T>)>,
+    ) {
+        match (old, new) {
+            (Some(Concrete::Contents(old)), Some(Concrete::Contents(new))) => {
+                if old != new {
+                    diff.push((key.clone(), Diff::Updated(old, new)));
+                }
+            }
+            (Some(Concrete::Tree(old)), Some(Concrete::Tree(mut new))) => {
+                for (step, child) in old {
+                    let other = new.remove(&step);
+                    key.push(step);
+                    Self::diff_at(key, Some(child), other, diff);
+                    key.pop();
+                }
+                for (step, child) in new {
+                    key.push(step);
+                    Self::diff_at(key, None, Some(child), diff);
+                    key.pop();
+                }
+            }
+            (old, new) => {
+                if let Some(old) = old {
+                    Self::leaves(key, old, &mut |key, v| {
+                        diff.push((key.clone(), Diff::Removed(v)))
+                    });
+                }
+                if let Some(new) = new {
+                    Self::leaves(key, new, &mut |key, v| {
+                        diff.push((key.clone(), Diff::Added(v)))
+                    });
+                }
+            }
+        }
+    }
+
+    fn leaves(key: &mut Key, node: Self, f: &mut impl FnMut(&Key, T)) {
+        match node {
+            Concrete::Contents(v) => f(key, v),
+            Concrete::Tree(t) => {
+                for (step, child) in t {
+                    key.push(step);
+                    Self::leaves(key, child, f);
+                    key.pop();
+                }
+            }
+        }
+    }
+}
+
 impl<T: Type> Concrete<T> {
     pub fn empty() -> Self {
         Concrete::Tree(BTreeMap::new())
@@ -95,6 +381,42 @@ impl<T: Type> Concrete<T> {
         }
     }
 
+    /// Set the node at `key`, replacing anything already there. Missing parents are created, a
+    /// parent that is set to contents is replaced by a tree.
+    pub(crate) fn insert(&mut self, key: &Key, node: Concrete<T>) {
+        let (last, parents) = match key.steps().split_last() {
+            Some(x) => x,
+            None => {
+                *self = node;
+                return;
+            }
+        };
+
+        let mut t = self;
+        for step in parents {
+            if t.is_contents() {
+                *t = Self::empty();
+            }
+            t = match t {
+                Concrete::Tree(t) => t.entry(step.clone()).or_insert_with(Concrete::empty),
+                Concrete::Contents(_) => unreachable!(),
+            };
+        }
+        t.add_tree_step(last.clone(), node);
+    }
+
+    /// Build a tree with the same structure, applying `f` to every contents node
+    pub fn map<U: Type>(&self, f: &impl Fn(&T) -> U) -> Concrete<U> {
+        match self {
+            Concrete::Contents(v) => Concrete::Contents(f(v)),
+            Concrete::Tree(t) => Concrete::Tree(
+                t.iter()
+                    .map(|(step, child)| (step.clone(), child.map(f)))
+                    .collect(),
+            ),
+        }
+    }
+
     pub fn remove_step(&mut self, key: impl AsRef<str>) {
         match self {
             Concrete::Tree(t) => {
@@ -1,4 +1,4 @@
-#[derive(Debug, PartialOrd)]
+#[derive(Debug, Clone, PartialOrd)]
 pub enum Bytes<'a> {
     Owned(Vec<u8>),
     Ref(&'a [u8]),
@@ -10,6 +10,49 @@ impl<'a> PartialEq for Bytes<'a> {
     }
 }
 
+impl<'a> Bytes<'a> {
+    pub fn empty() -> Self {
+        Bytes::Ref(&[])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.as_ref().is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.as_ref().len()
+    }
+
+    /// Create a new owned value containing `self` followed by `other`
+    pub fn concat(&self, other: impl AsRef<[u8]>) -> Bytes<'static> {
+        let mut data = Vec::with_capacity(self.len() + other.as_ref().len());
+        data.extend_from_slice(self.as_ref());
+        data.extend_from_slice(other.as_ref());
+        Bytes::Owned(data)
+    }
+
+    /// Split into two values borrowing from `self`, the first containing the bytes before `mid`
+    /// and the second the rest. Panics if `mid` is greater than `len`.
+    pub fn split_at(&self, mid: usize) -> (Bytes<'_>, Bytes<'_>) {
+        let (a, b) = self.as_ref().split_at(mid);
+        (Bytes::Ref(a), Bytes::Ref(b))
+    }
+
+    /// Convert into a `Vec`, only copying if the bytes are borrowed
+    pub fn into_vec(self) -> Vec<u8> {
+        match self {
+            Bytes::Owned(x) => x,
+            Bytes::Ref(x) => x.to_vec(),
+        }
+    }
+}
+
+impl<'a> Default for Bytes<'a> {
+    fn default() -> Self {
+        Bytes::empty()
+    }
+}
+
 impl<'a> AsRef<[u8]> for Bytes<'a> {
     fn as_ref(&self) -> &[u8] {
         match self {
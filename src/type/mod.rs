@@ -19,6 +19,59 @@ pub trait Type: Sized {
     fn encode_bin<W: std::io::Write>(&self, dest: &mut W) -> std::io::Result<usize>;
 
     fn decode_bin<R: std::io::Read>(src: &mut R) -> std::io::Result<Self>;
+
+    /// Decode a value stored at the end of `data`, starting at `start`. Types that wrap a byte
+    /// buffer can override this to reuse the allocation of `data` instead of allocating a new
+    /// buffer, the default implementation uses `decode_bin`.
+    fn decode_vec(data: Vec<u8>, start: usize) -> std::io::Result<Self> {
+        decode_slice(&data[start..])
+    }
+}
+
+/// `std::io::Read` wrapper that keeps track of how many bytes have been read
+pub(crate) struct Offset<R> {
+    inner: R,
+    offset: usize,
+}
+
+impl<R> Offset<R> {
+    pub(crate) fn new(inner: R) -> Offset<R> {
+        Offset { inner, offset: 0 }
+    }
+
+    pub(crate) fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for Offset<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+/// Decode a value from a byte slice, failures are reported as `Error::Decode` with the name of the
+/// type and the offset where decoding stopped
+pub(crate) fn decode_slice<T: Type>(data: &[u8]) -> std::io::Result<T> {
+    let mut src = Offset {
+        inner: data,
+        offset: 0,
+    };
+    T::decode_bin(&mut src).map_err(|source| {
+        crate::Error::Decode {
+            type_name: std::any::type_name::<T>(),
+            offset: src.offset,
+            source,
+        }
+        .into()
+    })
+}
+
+/// Like `decode_slice`, taking ownership of `data` so it can be reused by the decoded value
+pub(crate) fn decode_vec<T: Type>(data: Vec<u8>) -> std::io::Result<T> {
+    T::decode_vec(data, 0)
 }
 
 fn encode_int<W: std::io::Write>(mut n: i64, dest: &mut W) -> std::io::Result<usize> {
@@ -68,7 +121,7 @@ fn decode_int<R: std::io::Read>(src: &mut R) -> std::io::Result<Int> {
 
 impl Type for bool {
     fn encode_bin<W: std::io::Write>(&self, dest: &mut W) -> std::io::Result<usize> {
-        (if *self { 255 } else { 0 }).encode_bin(dest)
+        (if *self { 255u8 } else { 0u8 }).encode_bin(dest)
     }
 
     fn decode_bin<R: std::io::Read>(src: &mut R) -> std::io::Result<Self> {
@@ -184,6 +237,19 @@ impl Type for u64 {
     }
 }
 
+impl<const N: usize> Type for [u8; N] {
+    fn encode_bin<W: std::io::Write>(&self, dest: &mut W) -> std::io::Result<usize> {
+        dest.write_all(self)?;
+        Ok(N)
+    }
+
+    fn decode_bin<R: std::io::Read>(src: &mut R) -> std::io::Result<Self> {
+        let mut dest = [0u8; N];
+        src.read_exact(&mut dest)?;
+        Ok(dest)
+    }
+}
+
 impl Type for f64 {
     fn encode_bin<W: std::io::Write>(&self, dest: &mut W) -> std::io::Result<usize> {
         self.to_bits().encode_bin(dest)
@@ -287,6 +353,8 @@ impl<T: Type, U: Type, V: Type> Type for Triple<T, U, V> {
     }
 }
 
+/// Matches the encoding of `Repr.option`: a `0` tag byte for `None`, or `255` followed by the
+/// value for `Some`
 impl<T: Type> Type for Option<T> {
     fn encode_bin<W: std::io::Write>(&self, dest: &mut W) -> std::io::Result<usize> {
         match self {
@@ -306,6 +374,42 @@ impl<T: Type> Type for Option<T> {
             _ => T::decode_bin(src).map(Some),
         }
     }
+
+    fn decode_vec(data: Vec<u8>, start: usize) -> std::io::Result<Self> {
+        match data.get(start) {
+            Some(0) | None => decode_slice(&data[start..]),
+            Some(_) => T::decode_vec(data, start + 1).map(Some),
+        }
+    }
+}
+
+impl<T: Type, E: Type> Type for Result<T, E> {
+    fn encode_bin<W: std::io::Write>(&self, dest: &mut W) -> std::io::Result<usize> {
+        match self {
+            Ok(x) => {
+                let mut n = 0isize.encode_bin(dest)?;
+                n += x.encode_bin(dest)?;
+                Ok(n)
+            }
+            Err(x) => {
+                let mut n = 1isize.encode_bin(dest)?;
+                n += x.encode_bin(dest)?;
+                Ok(n)
+            }
+        }
+    }
+
+    fn decode_bin<R: std::io::Read>(src: &mut R) -> std::io::Result<Self> {
+        let i = isize::decode_bin(src)?;
+        match i {
+            0 => T::decode_bin(src).map(Ok),
+            1 => E::decode_bin(src).map(Err),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Invalid result tag",
+            )),
+        }
+    }
 }
 
 impl<T: Type> Type for &T {
@@ -380,11 +484,25 @@ impl<'a> Type for Bytes<'a> {
         let x = Vec::<u8>::decode_bin(src)?;
         Ok(Bytes::Owned(x))
     }
+
+    /// Reuses the allocation of `data` when the value fills the rest of it. The contents are
+    /// still moved to the start of the buffer to drop the length prefix, so this saves an
+    /// allocation but not the copy.
+    fn decode_vec(mut data: Vec<u8>, start: usize) -> std::io::Result<Self> {
+        let mut src = &data[start..];
+        let len = usize::decode_bin(&mut src).ok();
+        if len != Some(src.len()) {
+            return decode_slice(&data[start..]);
+        }
+        let header = data.len() - src.len();
+        data.drain(..header);
+        Ok(Bytes::Owned(data))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{Concrete, Str, Type};
+    use crate::{Blake2b, Bytes, Concrete, Info, Key, Str, Tree, TreeBuilder, Type};
 
     mod irmin {
         pub use crate::Type;
@@ -402,6 +520,21 @@ mod tests {
         assert_eq!(a, t);
     }
 
+    #[test]
+    fn test_decode_error_offset() {
+        let data = include_bytes!("../../tests/int_string_pair.bin");
+        let e = super::decode_slice::<(isize, String)>(&data[..3]).unwrap_err();
+        match crate::Error::of_io(&e) {
+            Some(crate::Error::Decode {
+                type_name, offset, ..
+            }) => {
+                assert_eq!(*type_name, std::any::type_name::<(isize, String)>());
+                assert_eq!(*offset, 3);
+            }
+            _ => panic!("Expected a decode error"),
+        }
+    }
+
     #[test]
     fn test_int_long_string_pair() {
         let s = [b'A'; 4096];
@@ -416,6 +549,30 @@ mod tests {
         assert_eq!(a, t);
     }
 
+    #[test]
+    fn test_option1() {
+        let a = (Some(42isize), None::<String>);
+        let data = include_bytes!("../../tests/option1.bin");
+        let mut output = Vec::new();
+        a.encode_bin(&mut output).unwrap();
+        assert_eq!(output.as_slice(), data);
+
+        let t: (Option<isize>, Option<String>) = Type::decode_bin(&mut &data[..]).unwrap();
+        assert_eq!(a, t);
+    }
+
+    #[test]
+    fn test_bool1() {
+        let a = (true, false);
+        let data = include_bytes!("../../tests/bool1.bin");
+        let mut output = Vec::new();
+        a.encode_bin(&mut output).unwrap();
+        assert_eq!(output.as_slice(), data);
+
+        let t: (bool, bool) = Type::decode_bin(&mut &data[..]).unwrap();
+        assert_eq!(a, t);
+    }
+
     #[test]
     fn test_struct1() {
         #[derive(Type, Debug, PartialEq)]
@@ -455,6 +612,100 @@ mod tests {
         assert_eq!(s, t);
     }
 
+    #[test]
+    fn test_fixed_size_bytes() {
+        let a = [1u8, 2, 3, 4];
+        let mut output = Vec::new();
+        assert_eq!(a.encode_bin(&mut output).unwrap(), 4);
+        assert_eq!(output.as_slice(), &a);
+
+        let t: [u8; 4] = Type::decode_bin(&mut output.as_slice()).unwrap();
+        assert_eq!(a, t);
+
+        let short: std::io::Result<[u8; 8]> = Type::decode_bin(&mut output.as_slice());
+        assert!(short.is_err());
+    }
+
+    #[test]
+    fn test_result() {
+        let a: Result<(), String> = Err("conflict".to_string());
+        let mut output = Vec::new();
+        a.encode_bin(&mut output).unwrap();
+        assert_eq!(output.as_slice(), b"\x01\x08conflict");
+
+        let t: Result<(), String> = Type::decode_bin(&mut output.as_slice()).unwrap();
+        assert_eq!(a, t);
+
+        let t: Result<(), String> = Type::decode_bin(&mut [0u8].as_slice()).unwrap();
+        assert_eq!(t, Ok(()));
+    }
+
+    #[test]
+    fn test_info1() {
+        let data = include_bytes!("../../tests/info1.bin");
+        let info = Info::decode_bin(&mut &data[..]).unwrap();
+        assert_eq!(info.date_seconds(), 1609459200);
+        assert_eq!(info.date_nanos(), Some(1_609_459_200_000_000_000));
+        assert_eq!(info.author(), "irmin");
+        assert_eq!(info.message(), "test");
+
+        let mut output = Vec::new();
+        info.encode_bin(&mut output).unwrap();
+        assert_eq!(output.as_slice(), data);
+
+        let mut info = info;
+        info.date = i64::MAX;
+        assert_eq!(info.date_nanos(), None);
+
+        assert_eq!(Info::commit(format_args!("Set {}", 1)).message(), "Set 1");
+        assert_eq!(
+            Info::merge("feature", "main").message(),
+            "Merge branch 'feature' into main"
+        );
+    }
+
+    #[test]
+    fn test_info_non_utf8() {
+        let info = Info::new().with_message(vec![b'a', 0xff, b'b']);
+        let mut output = Vec::new();
+        info.encode_bin(&mut output).unwrap();
+        let decoded = Info::decode_bin(&mut output.as_slice()).unwrap();
+        assert_eq!(decoded, info);
+        assert_eq!(decoded.message_bytes(), &[b'a', 0xff, b'b']);
+        assert_eq!(decoded.message(), "a\u{FFFD}b");
+        assert_eq!(decoded.author(), "irmin-rs");
+    }
+
+    #[test]
+    fn test_bytes() {
+        let empty = Bytes::empty();
+        let mut output = Vec::new();
+        assert_eq!(empty.encode_bin(&mut output).unwrap(), 1);
+        assert_eq!(output.as_slice(), [0]);
+        let x: Bytes = Type::decode_bin(&mut output.as_slice()).unwrap();
+        assert!(x.is_empty());
+
+        let mut output = Vec::new();
+        Some(Bytes::empty()).encode_bin(&mut output).unwrap();
+        let x: Option<Bytes> = Type::decode_bin(&mut output.as_slice()).unwrap();
+        assert_eq!(x, Some(Bytes::empty()));
+
+        let mut output = Vec::new();
+        Some(Bytes::from("abc".as_bytes()))
+            .encode_bin(&mut output)
+            .unwrap();
+        let x: Option<Bytes> = crate::r#type::decode_vec(output).unwrap();
+        assert_eq!(x.unwrap().as_ref(), b"abc");
+        let x: std::io::Result<Bytes> = crate::r#type::decode_vec(vec![3, 0]);
+        assert!(x.is_err());
+
+        let b = Bytes::from("abc".as_bytes()).concat("def");
+        assert_eq!(b.len(), 6);
+        let (x, y) = b.split_at(2);
+        assert_eq!((x.as_ref(), y.as_ref()), (&b"ab"[..], &b"cdef"[..]));
+        assert_eq!(b.into_vec(), b"abcdef");
+    }
+
     #[test]
     fn test_empty_tree() {
         let t: Concrete<Str> = Concrete::empty();
@@ -480,4 +731,75 @@ mod tests {
         let x: Concrete<Str> = Type::decode_bin(&mut output.as_slice()).unwrap();
         assert_eq!(t, x)
     }
+
+    #[test]
+    fn test_tree_encoder() {
+        let mut a: Concrete<Str> = Concrete::empty();
+        a.add_step("x", "1".into());
+        a.add_step("y", "2".into());
+        let mut c: Concrete<Str> = Concrete::empty();
+        c.add_tree_step("a", a);
+        c.add_tree_step("b", Concrete::empty());
+        c.add_step("c", "3".into());
+        let t: Tree<Str, Blake2b> = Tree::Concrete(c);
+
+        let mut data = Vec::new();
+        t.encode_bin(&mut data).unwrap();
+
+        for limit in [1, 4, 1024] {
+            let mut output = Vec::new();
+            let mut encoder = t.encoder();
+            let mut buf = Vec::new();
+            loop {
+                let more = encoder.fill(&mut buf, limit).unwrap();
+                output.extend_from_slice(&buf);
+                buf.clear();
+                if !more {
+                    break;
+                }
+            }
+            assert_eq!(output, data);
+        }
+    }
+
+    #[test]
+    fn test_tree_builder() {
+        let mut a: Concrete<Str> = Concrete::empty();
+        a.add_step("x", "1".into());
+        a.add_step("y", "2".into());
+        let mut c: Concrete<Str> = Concrete::empty();
+        c.add_tree_step("a", a);
+        c.add_step("c", "3".into());
+
+        let t: Tree<Str, Blake2b> = TreeBuilder::new()
+            .add(Key::new(["a", "x"]), "1".into())
+            .add(Key::new(["c"]), "3".into())
+            .add(Key::new(["a", "y"]), "2".into())
+            .build()
+            .unwrap();
+        assert_eq!(t, Tree::Concrete(c));
+
+        let conflicts: [&[&[&str]]; 3] = [&[&["a"], &["a"]], &[&["a"], &["a", "b"]], &[&[]]];
+        for keys in conflicts {
+            let mut builder = TreeBuilder::<Str>::new();
+            for key in keys {
+                builder = builder.add(Key::new(key), "x".into());
+            }
+            assert!(builder.build::<Blake2b>().is_err());
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json() {
+        let a = crate::Json(vec![(1, "a".to_string()), (2, "b".to_string())]);
+        let mut output = Vec::new();
+        a.encode_bin(&mut output).unwrap();
+
+        let s: String = Type::decode_bin(&mut output.as_slice()).unwrap();
+        assert_eq!(s, r#"[[1,"a"],[2,"b"]]"#);
+
+        let b: crate::Json<Vec<(i32, String)>> = Type::decode_bin(&mut output.as_slice()).unwrap();
+        assert_eq!(a, b);
+    }
 }
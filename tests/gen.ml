@@ -29,7 +29,26 @@ let empty_tree () = save "empty_tree" Store.Tree.concrete_t (`Tree [])
 let tree1 () =
   save "tree1" Store.Tree.concrete_t (`Tree [ ("foo", `Contents ("bar", ())) ])
 
+let info1 () =
+  save "info1" Store.Info.t
+    (Store.Info.v ~author:"irmin" ~message:"test" 1609459200L)
+
+let option1 () =
+  save "option1" Repr.(pair (option int) (option string)) (Some 42, None)
+
+let bool1 () = save "bool1" Repr.(pair bool bool) (true, false)
+
 let tests =
-  [ int_string_pair; int_long_string_pair; struct1; enum1; empty_tree; tree1 ]
+  [
+    int_string_pair;
+    int_long_string_pair;
+    struct1;
+    enum1;
+    empty_tree;
+    tree1;
+    info1;
+    option1;
+    bool1;
+  ]
 
 let () = Lwt_main.run (Lwt_list.iter_s (fun x -> x ()) tests)